@@ -0,0 +1,167 @@
+use crate::*;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+
+pub const PBKDF2_ROUNDS: u32 = 100_000;
+pub const SALT_LEN: usize = 16;
+pub const HASH_LEN: usize = 32;
+pub const MAX_JOIN_ATTEMPTS: u32 = 5;
+pub const ATTEMPT_WINDOW: Duration = Duration::from_secs(60);
+pub const BASE_BACKOFF: Duration = Duration::from_secs(2);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(300);
+pub const MAX_VERIFICATIONS: usize = 4;
+
+#[derive(Clone)]
+pub struct PasswordHash {
+    salt: [u8; SALT_LEN],
+    hash: [u8; HASH_LEN],
+}
+
+impl PasswordHash {
+    pub fn new(password: &[u8]) -> Self {
+        let mut salt = [0; SALT_LEN];
+        rand::thread_rng().fill(&mut salt);
+        Self {
+            hash: derive(password, &salt),
+            salt,
+        }
+    }
+
+    pub fn verify(&self, password: &[u8]) -> bool {
+        derive(password, &self.salt).ct_eq(&self.hash).into()
+    }
+}
+
+fn derive(password: &[u8], salt: &[u8]) -> [u8; HASH_LEN] {
+    let mut hash = [0; HASH_LEN];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, PBKDF2_ROUNDS, &mut hash);
+    hash
+}
+
+struct Attempts {
+    failures: u32,
+    in_flight: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Attempts {
+    fn new(now: Instant) -> Self {
+        Self {
+            failures: 0,
+            in_flight: 0,
+            last_failure: now,
+            locked_until: None,
+        }
+    }
+
+    fn expired(&self, now: Instant) -> bool {
+        self.in_flight == 0
+            && now >= self.last_failure + ATTEMPT_WINDOW
+            && self.locked_until.map_or(true, |x| now >= x)
+    }
+}
+
+#[derive(Default)]
+pub struct JoinLimiter {
+    attempts: HashMap<IpAddr, Attempts>,
+}
+
+impl JoinLimiter {
+    pub fn reserve(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        self.attempts.retain(|_, x| !x.expired(now));
+        let attempts = self.attempts.entry(ip).or_insert(Attempts::new(now));
+        if attempts.locked_until.map_or(false, |x| now < x) {
+            return false;
+        }
+        let budget = MAX_JOIN_ATTEMPTS.saturating_sub(attempts.failures).max(1);
+        if attempts.in_flight >= budget {
+            return false;
+        }
+        attempts.in_flight += 1;
+        true
+    }
+
+    pub fn release(&mut self, ip: IpAddr) {
+        if let Some(attempts) = self.attempts.get_mut(&ip) {
+            attempts.in_flight = attempts.in_flight.saturating_sub(1);
+        }
+    }
+
+    pub fn failed(&mut self, ip: IpAddr) {
+        self.release(ip);
+        let now = Instant::now();
+        let attempts = self.attempts.entry(ip).or_insert(Attempts::new(now));
+        attempts.failures += 1;
+        attempts.last_failure = now;
+        if attempts.failures >= MAX_JOIN_ATTEMPTS {
+            let exponent = (attempts.failures - MAX_JOIN_ATTEMPTS).min(16);
+            let backoff = (BASE_BACKOFF * 2u32.pow(exponent)).min(MAX_BACKOFF);
+            attempts.locked_until = Some(now + backoff);
+        }
+    }
+
+    pub fn succeeded(&mut self, ip: IpAddr) {
+        self.release(ip);
+        if let Some(attempts) = self.attempts.get_mut(&ip) {
+            attempts.failures = 0;
+            attempts.locked_until = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: [u8; 4] = [127, 0, 0, 1];
+
+    #[test]
+    fn in_flight_attempts_count_against_the_budget() {
+        let mut limiter = JoinLimiter::default();
+        let ip = IpAddr::from(IP);
+        for _ in 0..MAX_JOIN_ATTEMPTS {
+            assert!(limiter.reserve(ip));
+        }
+        assert!(!limiter.reserve(ip));
+        assert!(limiter.reserve(IpAddr::from([10, 0, 0, 1])));
+        limiter.release(ip);
+        assert!(limiter.reserve(ip));
+    }
+
+    #[test]
+    fn failures_lock_out_until_backoff() {
+        let mut limiter = JoinLimiter::default();
+        let ip = IpAddr::from(IP);
+        for _ in 0..MAX_JOIN_ATTEMPTS - 1 {
+            assert!(limiter.reserve(ip));
+            limiter.failed(ip);
+        }
+        assert!(limiter.reserve(ip));
+        assert!(!limiter.reserve(ip));
+        limiter.failed(ip);
+        assert!(!limiter.reserve(ip));
+        limiter.attempts.get_mut(&ip).unwrap().locked_until = Some(Instant::now());
+        assert!(limiter.reserve(ip));
+        assert!(!limiter.reserve(ip));
+    }
+
+    #[test]
+    fn success_clears_failures() {
+        let mut limiter = JoinLimiter::default();
+        let ip = IpAddr::from(IP);
+        for _ in 0..MAX_JOIN_ATTEMPTS - 1 {
+            assert!(limiter.reserve(ip));
+            limiter.failed(ip);
+        }
+        assert!(limiter.reserve(ip));
+        limiter.succeeded(ip);
+        for _ in 0..MAX_JOIN_ATTEMPTS {
+            assert!(limiter.reserve(ip));
+        }
+    }
+}
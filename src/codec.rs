@@ -0,0 +1,518 @@
+use crate::*;
+use deku::bitvec::{BitVec, BitView, Msb0};
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+pub const HEADER_LEN: usize = 5;
+pub const MAX_FRAME_LEN: usize = 1 << 20;
+
+pub const PROTOCOL_VERSION: u16 = 2;
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+pub const CAP_QUANTIZED: u32 = 0x1;
+pub const CAPABILITIES: u32 = CAP_QUANTIZED;
+
+pub fn negotiate(version: u16) -> Option<u16> {
+    if version < MIN_PROTOCOL_VERSION {
+        return None;
+    }
+    Some(version.min(PROTOCOL_VERSION))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageKind {
+    ServerRequest = 0x1,
+    ServerResponse = 0x2,
+    JoinResponse = 0x3,
+    PlayerSignal = 0x4,
+    ResponseSignal = 0x5,
+    EntityTable = 0x6,
+    QuantizedSignal = 0x7,
+    InputBatch = 0x8,
+    MatchStatus = 0x9,
+    Scoreboard = 0xA,
+    PlayerLeft = 0xB,
+}
+
+impl MessageKind {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        use MessageKind::*;
+        match value {
+            0x1 => Some(ServerRequest),
+            0x2 => Some(ServerResponse),
+            0x3 => Some(JoinResponse),
+            0x4 => Some(PlayerSignal),
+            0x5 => Some(ResponseSignal),
+            0x6 => Some(EntityTable),
+            0x7 => Some(QuantizedSignal),
+            0x8 => Some(InputBatch),
+            0x9 => Some(MatchStatus),
+            0xA => Some(Scoreboard),
+            0xB => Some(PlayerLeft),
+            _ => None,
+        }
+    }
+}
+
+pub trait Message: for<'a> DekuContainerRead<'a> + DekuContainerWrite {
+    const KIND: MessageKind;
+    const DELIVERY: Delivery = Delivery::ReliableOrdered;
+
+    fn payload(&self, _version: u16) -> Result<Vec<u8>, DekuError> {
+        self.to_bytes()
+    }
+
+    fn from_payload(payload: &[u8], _version: u16) -> Result<Self, DekuError>
+    where
+        Self: Sized,
+    {
+        Ok(Self::from_bytes((payload, 0))?.1)
+    }
+}
+
+fn write_versioned<T: DekuWrite<u16>>(message: &T, version: u16) -> Result<Vec<u8>, DekuError> {
+    let mut output = BitVec::<u8, Msb0>::new();
+    message.write(&mut output, version)?;
+    Ok(output.into_vec())
+}
+
+fn read_versioned<T: for<'a> DekuRead<'a, u16>>(
+    payload: &[u8],
+    version: u16,
+) -> Result<T, DekuError> {
+    Ok(T::read(payload.view_bits::<Msb0>(), version)?.1)
+}
+
+impl Message for ServerRequest {
+    const KIND: MessageKind = MessageKind::ServerRequest;
+}
+
+impl Message for ServerResponse {
+    const KIND: MessageKind = MessageKind::ServerResponse;
+}
+
+impl Message for JoinResponse {
+    const KIND: MessageKind = MessageKind::JoinResponse;
+}
+
+impl Message for PlayerSignal {
+    const KIND: MessageKind = MessageKind::PlayerSignal;
+    const DELIVERY: Delivery = Delivery::UnreliableSequenced;
+
+    fn payload(&self, version: u16) -> Result<Vec<u8>, DekuError> {
+        write_versioned(self, version)
+    }
+
+    fn from_payload(payload: &[u8], version: u16) -> Result<Self, DekuError> {
+        read_versioned(payload, version)
+    }
+}
+
+impl Message for ResponseSignal {
+    const KIND: MessageKind = MessageKind::ResponseSignal;
+    const DELIVERY: Delivery = Delivery::UnreliableSequenced;
+
+    fn payload(&self, version: u16) -> Result<Vec<u8>, DekuError> {
+        write_versioned(self, version)
+    }
+
+    fn from_payload(payload: &[u8], version: u16) -> Result<Self, DekuError> {
+        read_versioned(payload, version)
+    }
+}
+
+impl Message for QuantizedSignal {
+    const KIND: MessageKind = MessageKind::QuantizedSignal;
+    const DELIVERY: Delivery = Delivery::UnreliableSequenced;
+
+    fn payload(&self, version: u16) -> Result<Vec<u8>, DekuError> {
+        write_versioned(self, version)
+    }
+
+    fn from_payload(payload: &[u8], version: u16) -> Result<Self, DekuError> {
+        read_versioned(payload, version)
+    }
+}
+
+impl Message for InputBatch {
+    const KIND: MessageKind = MessageKind::InputBatch;
+    const DELIVERY: Delivery = Delivery::UnreliableSequenced;
+
+    fn payload(&self, version: u16) -> Result<Vec<u8>, DekuError> {
+        write_versioned(self, version)
+    }
+
+    fn from_payload(payload: &[u8], version: u16) -> Result<Self, DekuError> {
+        read_versioned(payload, version)
+    }
+}
+
+impl Message for EntityTable {
+    const KIND: MessageKind = MessageKind::EntityTable;
+}
+
+impl Message for MatchStatus {
+    const KIND: MessageKind = MessageKind::MatchStatus;
+
+    fn payload(&self, version: u16) -> Result<Vec<u8>, DekuError> {
+        write_versioned(self, version)
+    }
+
+    fn from_payload(payload: &[u8], version: u16) -> Result<Self, DekuError> {
+        read_versioned(payload, version)
+    }
+}
+
+impl Message for Scoreboard {
+    const KIND: MessageKind = MessageKind::Scoreboard;
+}
+
+impl Message for PlayerLeft {
+    const KIND: MessageKind = MessageKind::PlayerLeft;
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Io(std::io::Error),
+    Decode(DekuError),
+    UnknownKind(u8),
+    UnexpectedKind(MessageKind),
+    TooLarge(usize),
+    Malformed(usize),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Io(err) => write!(f, "io error: {}", err),
+            CodecError::Decode(err) => write!(f, "decode error: {}", err),
+            CodecError::UnknownKind(kind) => write!(f, "unknown message kind {}", kind),
+            CodecError::UnexpectedKind(kind) => write!(f, "unexpected message kind {:?}", kind),
+            CodecError::TooLarge(len) => write!(f, "frame of {} bytes exceeds limit", len),
+            CodecError::Malformed(len) => write!(f, "{} bytes do not hold exactly one frame", len),
+        }
+    }
+}
+
+impl From<std::io::Error> for CodecError {
+    fn from(err: std::io::Error) -> Self {
+        CodecError::Io(err)
+    }
+}
+
+impl From<DekuError> for CodecError {
+    fn from(err: DekuError) -> Self {
+        CodecError::Decode(err)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub kind: MessageKind,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn decode<T: Message>(&self) -> Result<T, CodecError> {
+        self.decode_version(PROTOCOL_VERSION)
+    }
+
+    pub fn decode_version<T: Message>(&self, version: u16) -> Result<T, CodecError> {
+        if self.kind != T::KIND {
+            return Err(CodecError::UnexpectedKind(self.kind));
+        }
+        Ok(T::from_payload(&self.payload, version)?)
+    }
+}
+
+pub fn encode<T: Message>(message: &T) -> Result<Vec<u8>, CodecError> {
+    encode_version(message, PROTOCOL_VERSION)
+}
+
+pub fn encode_version<T: Message>(message: &T, version: u16) -> Result<Vec<u8>, CodecError> {
+    let payload = message.payload(version)?;
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(CodecError::TooLarge(payload.len()));
+    }
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    bytes.push(T::KIND as u8);
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+fn parse_header(header: &[u8]) -> Result<(usize, MessageKind), CodecError> {
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(CodecError::TooLarge(len));
+    }
+    let kind = MessageKind::from_u8(header[4]).ok_or(CodecError::UnknownKind(header[4]))?;
+    Ok((len, kind))
+}
+
+pub fn decode_frame(bytes: &[u8]) -> Result<Frame, CodecError> {
+    let mut decoder = FrameDecoder::new();
+    decoder.extend(bytes);
+    match decoder.next_frame()? {
+        Some(frame) if decoder.buffer.is_empty() => Ok(frame),
+        _ => Err(CodecError::Malformed(bytes.len())),
+    }
+}
+
+#[derive(Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self { buffer: Vec::new() }
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, CodecError> {
+        if self.buffer.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let (len, kind) = parse_header(&self.buffer[..HEADER_LEN])?;
+        if self.buffer.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+        let payload = self.buffer[HEADER_LEN..HEADER_LEN + len].to_vec();
+        self.buffer.drain(..HEADER_LEN + len);
+        Ok(Some(Frame { kind, payload }))
+    }
+}
+
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Frame, CodecError> {
+    let mut header = [0; HEADER_LEN];
+    reader.read_exact(&mut header).await?;
+    let (len, kind) = parse_header(&header)?;
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+    Ok(Frame { kind, payload })
+}
+
+pub async fn read_message<T: Message, R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<T, CodecError> {
+    read_frame(reader).await?.decode()
+}
+
+pub async fn write_message<T: Message, W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &T,
+) -> Result<(), CodecError> {
+    writer.write_all(&encode(message)?).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::DEFAULT_TICK_RATE;
+
+    fn left(id: EntityId) -> PlayerLeft {
+        PlayerLeft {
+            id,
+            reason: LeaveReason::TimedOut,
+        }
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let bytes = encode(&left(7)).unwrap();
+        assert_eq!(bytes.len(), HEADER_LEN + 3);
+        assert_eq!(bytes[4], MessageKind::PlayerLeft as u8);
+        let message = decode_frame(&bytes)
+            .unwrap()
+            .decode::<PlayerLeft>()
+            .unwrap();
+        assert_eq!(message.id, 7);
+        assert_eq!(message.reason, LeaveReason::TimedOut);
+    }
+
+    #[test]
+    fn decoder_reassembles_split_frames() {
+        let mut bytes = encode(&left(1)).unwrap();
+        bytes.extend(encode(&left(2)).unwrap());
+        let mut decoder = FrameDecoder::new();
+        let mut frames = Vec::new();
+        for byte in bytes {
+            decoder.extend(&[byte]);
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                frames.push(frame.decode::<PlayerLeft>().unwrap().id);
+            }
+        }
+        assert_eq!(frames, vec![1, 2]);
+    }
+
+    #[test]
+    fn rejects_oversized_and_unknown_frames() {
+        let mut header = ((MAX_FRAME_LEN + 1) as u32).to_be_bytes().to_vec();
+        header.push(MessageKind::PlayerLeft as u8);
+        assert!(matches!(
+            decode_frame(&header),
+            Err(CodecError::TooLarge(len)) if len == MAX_FRAME_LEN + 1
+        ));
+        assert!(matches!(
+            decode_frame(&[0, 0, 0, 0, 0xFF]),
+            Err(CodecError::UnknownKind(0xFF))
+        ));
+    }
+
+    #[test]
+    fn rejects_trailing_and_truncated_datagrams() {
+        let mut bytes = encode(&left(4)).unwrap();
+        assert!(matches!(
+            decode_frame(&bytes[..bytes.len() - 1]),
+            Err(CodecError::Malformed(_))
+        ));
+        bytes.push(0);
+        assert!(matches!(
+            decode_frame(&bytes),
+            Err(CodecError::Malformed(len)) if len == bytes.len()
+        ));
+    }
+
+    #[test]
+    fn rejects_unexpected_kind() {
+        let frame = decode_frame(&encode(&left(3)).unwrap()).unwrap();
+        assert!(matches!(
+            frame.decode::<Scoreboard>(),
+            Err(CodecError::UnexpectedKind(MessageKind::PlayerLeft))
+        ));
+    }
+
+    fn new_session(version: u16) -> NewSessionRequest {
+        let mut request = NewSessionRequest::new("room", "secret");
+        request.version = version;
+        request.tick_rate = 30;
+        request.mode = ModeKind::CaptureTheFlag;
+        request.friendly_fire = true;
+        request.public = false;
+        request.reserved_slots = 2;
+        request.spectator_limit = 4;
+        request
+    }
+
+    fn round_trip_request(request: ServerRequest) -> ServerRequest {
+        decode_frame(&encode(&request).unwrap())
+            .unwrap()
+            .decode::<ServerRequest>()
+            .unwrap()
+    }
+
+    #[test]
+    fn new_session_oldest_and_newest() {
+        let ServerRequest::NewSession(oldest) =
+            round_trip_request(ServerRequest::NewSession(new_session(MIN_PROTOCOL_VERSION)))
+        else {
+            panic!("expected a new session request");
+        };
+        assert_eq!(oldest.version, MIN_PROTOCOL_VERSION);
+        assert_eq!(oldest.id, b"room");
+        assert_eq!(oldest.password, b"secret");
+        assert_eq!(oldest.tick_rate, DEFAULT_TICK_RATE);
+        assert_eq!(oldest.mode, ModeKind::Deathmatch);
+        assert!(!oldest.friendly_fire);
+        assert!(!oldest.public);
+        assert_eq!(oldest.reserved_slots, 0);
+        assert_eq!(oldest.spectator_limit, 0);
+
+        let ServerRequest::NewSession(newest) =
+            round_trip_request(ServerRequest::NewSession(new_session(PROTOCOL_VERSION)))
+        else {
+            panic!("expected a new session request");
+        };
+        assert_eq!(newest.tick_rate, 30);
+        assert_eq!(newest.mode, ModeKind::CaptureTheFlag);
+        assert!(newest.friendly_fire);
+        assert!(!newest.public);
+        assert_eq!(newest.reserved_slots, 2);
+        assert_eq!(newest.spectator_limit, 4);
+    }
+
+    #[test]
+    fn join_session_oldest_and_newest() {
+        for (version, team) in [(MIN_PROTOCOL_VERSION, TEAM_NONE), (PROTOCOL_VERSION, 1)] {
+            let mut request = JoinSessionRequest::new("room", "secret");
+            request.version = version;
+            request.team = 1;
+            let ServerRequest::JoinSession(request) =
+                round_trip_request(ServerRequest::JoinSession(request))
+            else {
+                panic!("expected a join session request");
+            };
+            assert_eq!(request.version, version);
+            assert_eq!(request.id, b"room");
+            assert_eq!(request.password, b"secret");
+            assert_eq!(request.team, team);
+        }
+    }
+
+    #[test]
+    fn response_signal_oldest_and_newest() {
+        let mut signal = ResponseSignal::default();
+        signal.id = 3;
+        signal.snapshot = 12;
+        signal.baseline = 10;
+        signal.last_input = 40;
+        signal.tick = 99;
+        signal.health = 80;
+        signal.armour = 20;
+        signal.state = PlayerState::Dead;
+        signal.team = 1;
+        signal.translation = [1.0, 2.0, 3.0];
+        let mut other = ResponseSignal::default();
+        other.id = 4;
+        other.health = 55;
+        signal.players.push(other);
+        signal.removed.push(9);
+        signal.update().unwrap();
+
+        let bytes = encode_version(&signal, MIN_PROTOCOL_VERSION).unwrap();
+        let oldest = decode_frame(&bytes)
+            .unwrap()
+            .decode_version::<ResponseSignal>(MIN_PROTOCOL_VERSION)
+            .unwrap();
+        assert_eq!(oldest.id, 3);
+        assert_eq!(
+            (oldest.snapshot, oldest.baseline, oldest.last_input),
+            (12, 10, 40)
+        );
+        assert_eq!(oldest.tick, 0);
+        assert_eq!(oldest.health, 0);
+        assert_eq!(oldest.state, PlayerState::Alive);
+        assert_eq!(oldest.team, TEAM_NONE);
+        assert_eq!(oldest.translation, [1.0, 2.0, 3.0]);
+        assert_eq!(oldest.players.len(), 1);
+        assert_eq!(oldest.players[0].id, 4);
+        assert!(oldest.removed.is_empty());
+
+        let bytes = encode_version(&signal, PROTOCOL_VERSION).unwrap();
+        let newest = decode_frame(&bytes)
+            .unwrap()
+            .decode_version::<ResponseSignal>(PROTOCOL_VERSION)
+            .unwrap();
+        assert_eq!(newest.tick, 99);
+        assert_eq!((newest.health, newest.armour), (80, 20));
+        assert_eq!(newest.state, PlayerState::Dead);
+        assert_eq!(newest.team, 1);
+        assert_eq!(newest.players[0].health, 55);
+        assert_eq!(newest.removed, vec![9]);
+    }
+
+    #[tokio::test]
+    async fn reads_and_writes_streams() {
+        let mut output = Vec::new();
+        write_message(&mut output, &left(9)).await.unwrap();
+        let message = read_message::<PlayerLeft, _>(&mut output.as_slice())
+            .await
+            .unwrap();
+        assert_eq!(message.id, 9);
+    }
+}
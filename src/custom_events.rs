@@ -7,6 +7,10 @@ pub fn handle_collision(
 ) {
     while let Ok(collision_event) = collision_recv.try_recv() {
         println!("inside!");
+        if collision_event.started() {
+            manager.projectile_impact(collision_event.collider1());
+            manager.projectile_impact(collision_event.collider2());
+        }
         if !collision_event.sensor() {
             continue;
         }
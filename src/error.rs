@@ -0,0 +1,78 @@
+use crate::*;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ServerError {
+    Io(std::io::Error),
+    Decode(CodecError),
+    Protocol(Reason),
+    SessionClosed,
+    NotListening,
+    Scene(String),
+    Loadout(String),
+}
+
+impl ServerError {
+    pub fn reason(&self) -> Reason {
+        match self {
+            ServerError::Protocol(reason) => *reason,
+            ServerError::Decode(_) => Reason::InvalidRequestFormat,
+            ServerError::SessionClosed => Reason::IdDoesntExist,
+            ServerError::Io(_)
+            | ServerError::NotListening
+            | ServerError::Scene(_)
+            | ServerError::Loadout(_) => Reason::Unavailable,
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Io(err) => write!(f, "io error: {}", err),
+            ServerError::Decode(err) => write!(f, "{}", err),
+            ServerError::Protocol(reason) => write!(f, "request rejected: {:?}", reason),
+            ServerError::SessionClosed => write!(f, "session is closed"),
+            ServerError::NotListening => write!(f, "server is not listening"),
+            ServerError::Scene(err) => write!(f, "invalid scene: {}", err),
+            ServerError::Loadout(err) => write!(f, "invalid loadout: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+impl From<std::io::Error> for ServerError {
+    fn from(err: std::io::Error) -> Self {
+        ServerError::Io(err)
+    }
+}
+
+impl From<CodecError> for ServerError {
+    fn from(err: CodecError) -> Self {
+        match err {
+            CodecError::Io(err) => ServerError::Io(err),
+            err => ServerError::Decode(err),
+        }
+    }
+}
+
+impl From<DekuError> for ServerError {
+    fn from(err: DekuError) -> Self {
+        ServerError::Decode(CodecError::Decode(err))
+    }
+}
+
+impl From<Reason> for ServerError {
+    fn from(reason: Reason) -> Self {
+        ServerError::Protocol(reason)
+    }
+}
+
+pub fn parse_id(id: &[u8]) -> Result<String, ServerError> {
+    let id = String::from_utf8(id.to_vec()).map_err(|_| Reason::InvalidIdFormat)?;
+    if id.is_empty() || id.len() > u8::MAX as usize {
+        return Err(Reason::InvalidIdFormat.into());
+    }
+    Ok(id)
+}
@@ -1,5 +1,5 @@
 use crate::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 
 use rapier3d::control::CharacterCollision;
 use rapier3d::control::KinematicCharacterController;
@@ -9,12 +9,29 @@ use rapier3d::{
     na::{Const, OPoint},
 };
 use raylib::prelude::*;
-use tokio::time::Instant;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
 use crate::player::Player;
-use crate::reader::load_scene;
+use crate::reader::{flag_base, load_scene, spawn_point, team_only, FlagBase, SpawnPoint};
 use crate::{lights, objects::*, S};
 
+pub const BOUNDS_MARGIN: f32 = 50.0;
+pub const DEFAULT_TICK_RATE: u8 = 60;
+pub const MIN_TICK_RATE: u8 = 10;
+pub const MAX_TICK_RATE: u8 = 128;
+pub const MAX_QUEUED_INPUTS: usize = 16;
+pub const PLAYER_GRAVITY: f32 = -9.81;
+pub const SPAWN_CLEARANCE: f32 = 2.0;
+pub const ENTITY_REUSE_DELAY: u32 = SNAPSHOT_HISTORY as u32 + 1;
+
+pub struct PlayerUpdate {
+    pub snapshot: Snapshot,
+    pub table: EntityTable,
+    pub status: Option<MatchStatus>,
+    pub scoreboard: Option<Scoreboard>,
+    pub left: Vec<PlayerLeft>,
+}
+
 #[derive(Clone)]
 pub struct GameManager {
     pub colliders: ColliderSet,
@@ -33,39 +50,72 @@ pub struct GameManager {
     pub objects: Vec<(NetworkObject, ColliderHandle, RigidBodyHandle)>,
     pub network_objects: Vec<NetworkObject>,
     pub players: HashMap<u64, Player>,
+    pub snapshots: HashMap<u64, SnapshotHistory>,
     pub default_player: Option<Player>,
-    pub sender: Sender<Player>,
-    pub receiver: Receiver<Player>,
-    pub nsender: Sender<(Player, ResponseSignal)>,
-    pub nreceiver: Receiver<(Player, ResponseSignal)>,
+    pub spawn_points: Vec<SpawnPoint>,
+    pub flag_bases: Vec<FlagBase>,
+    pub mode: Box<dyn GameMode>,
+    pub phase: MatchPhase,
+    pub phase_time: f32,
+    pub scene: String,
+    pub friendly_fire: bool,
+    pub stats: HashMap<u64, PlayerStats>,
+    match_status: Option<MatchStatus>,
+    pub loadout: Vec<WeaponDef>,
+    pub sender: Sender<(u64, PlayerSignal)>,
+    pub receiver: Receiver<(u64, PlayerSignal)>,
+    pub leave_sender: Sender<(u64, LeaveReason)>,
+    pub leave_receiver: Receiver<(u64, LeaveReason)>,
+    pub outbound: HashMap<u64, UnboundedSender<PlayerUpdate>>,
+    pub collision_sender: Sender<CollisionEvent>,
+    pub collision_receiver: Receiver<CollisionEvent>,
+    pub contact_sender: Sender<ContactForceEvent>,
+    pub contact_receiver: Receiver<ContactForceEvent>,
+    inputs: HashMap<u64, VecDeque<PlayerSignal>>,
+    pub entities: BTreeMap<u32, EntityEntry>,
+    pub encoding: SnapshotEncoding,
+    pub bounds: Bounds,
+    pub tick: u32,
+    history: ColliderHistory,
+    hits: Vec<HitEvent>,
+    pub projectiles: Vec<Projectile>,
+    removed: Vec<EntityId>,
+    left: Vec<PlayerLeft>,
+    next_entity: EntityId,
+    entity_serial: u32,
+    free_entities: VecDeque<(u32, EntityId)>,
 }
 
 impl GameManager {
-    fn move_player(&mut self, player: &mut Player) {
+    fn move_player(&mut self, player: &mut Player, previous: Vector3) {
         let mut collisions = vec![];
-        if let Some(player_now) = self.players.get(&player.id) {
-            let player_mov = player.position - player_now.position;
-            let mov = player.obj.move_shape(
-                self.dt,
-                &mut self.bodies,
-                &mut self.colliders,
-                &self.query_pipeline,
-                &rapier3d::parry::shape::Ball::new(2.0),
-                &Isometry::translation(
-                    player_now.position.x,
-                    player_now.position.y,
-                    player_now.position.z,
-                ),
-                vector![player_mov.x, player_mov.y, player_mov.z],
-                QueryFilter::default().exclude_collider(player.collider),
-                |collision| collisions.push(collision),
-            );
-            player.position = player_now.position
-                + Vector3::new(mov.translation.x, mov.translation.y, mov.translation.z);
-
-            self.solve_collisions(player, collisions, self.dt);
-            self.update_collider(player);
+        let player_mov = player.position - previous;
+        let mov = player.obj.move_shape(
+            self.dt,
+            &mut self.bodies,
+            &mut self.colliders,
+            &self.query_pipeline,
+            &rapier3d::parry::shape::Ball::new(2.0),
+            &Isometry::translation(previous.x, previous.y, previous.z),
+            vector![player_mov.x, player_mov.y, player_mov.z],
+            QueryFilter::default()
+                .exclude_collider(player.collider)
+                .exclude_sensors()
+                .groups(team_groups(player.team)),
+            |collision| collisions.push(collision),
+        );
+        player.position =
+            previous + Vector3::new(mov.translation.x, mov.translation.y, mov.translation.z);
+        if mov.grounded && !player.grounded {
+            player.land(player.velocity.y);
+        }
+        player.grounded = mov.grounded;
+        if mov.grounded && player.velocity.y < 0.0 {
+            player.velocity.y = 0.0;
         }
+
+        self.solve_collisions(player, collisions, self.dt);
+        self.update_collider(player);
     }
     fn solve_collisions(
         &mut self,
@@ -96,11 +146,344 @@ impl GameManager {
                 player.position.z
             ]);
         }
+        let head = player.head_position();
+        if let Some(data) = self.colliders.get_mut(player.head) {
+            data.set_translation(vector![head.x, head.y, head.z]);
+        }
     }
-    pub async fn update(&mut self, pipeline: &mut PhysicsPipeline, instant: &mut Instant) {
-        if instant.elapsed().as_secs_f32() < self.dt {
+
+    fn set_player_team(&mut self, player: &Player) {
+        for handle in [player.collider, player.head] {
+            if let Some(collider) = self.colliders.get_mut(handle) {
+                collider.set_collision_groups(team_groups(player.team));
+            }
+        }
+    }
+
+    fn can_damage(&self, attacker: u64, target: &Player) -> bool {
+        if self.friendly_fire || attacker == target.id {
+            return true;
+        }
+        let team = self.players.get(&attacker).and_then(|x| x.team);
+        !target.teammate(team)
+    }
+
+    fn record(&mut self, id: u64, update: impl FnOnce(&mut PlayerStats)) {
+        if self.phase != MatchPhase::InProgress {
             return;
         }
+        if let Some(stats) = self.stats.get_mut(&id) {
+            update(stats);
+        }
+    }
+
+    fn reset_stats(&mut self) {
+        self.stats = self
+            .players
+            .iter()
+            .filter(|(_, x)| !x.spectating())
+            .map(|(id, x)| (*id, PlayerStats::new(x.entity, x.team)))
+            .collect();
+    }
+
+    fn set_player_collision(&mut self, player: &Player, enabled: bool) {
+        for handle in [player.collider, player.head] {
+            if let Some(collider) = self.colliders.get_mut(handle) {
+                collider.set_enabled(enabled);
+            }
+        }
+    }
+
+    fn spawn_occupied(&self, position: Vector3) -> bool {
+        let mut occupied = false;
+        self.query_pipeline.intersections_with_shape(
+            &self.bodies,
+            &self.colliders,
+            &Isometry::translation(position.x, position.y, position.z),
+            &rapier3d::parry::shape::Ball::new(SPAWN_CLEARANCE),
+            QueryFilter::default().exclude_sensors(),
+            |handle| {
+                let dynamic = self
+                    .colliders
+                    .get(handle)
+                    .and_then(|x| x.parent())
+                    .and_then(|x| self.bodies.get(x))
+                    .map_or(false, |x| x.is_dynamic());
+                occupied = dynamic || self.players.values().any(|x| x.collider == handle);
+                !occupied
+            },
+        );
+        occupied
+    }
+
+    fn select_spawn(&self, player: u64, team: Option<u8>) -> Vector3 {
+        let fallback = self
+            .default_player
+            .as_ref()
+            .map(|x| x.position)
+            .unwrap_or(Vector3::zero());
+        let enemies = self
+            .players
+            .values()
+            .filter(|x| x.id != player && x.alive() && !x.teammate(team))
+            .map(|x| x.position)
+            .collect::<Vec<Vector3>>();
+        let allowed = self
+            .spawn_points
+            .iter()
+            .filter(|x| team.is_none() || x.team.is_none() || x.team == team)
+            .collect::<Vec<&SpawnPoint>>();
+        let free = allowed
+            .iter()
+            .copied()
+            .filter(|x| !self.spawn_occupied(x.position))
+            .collect::<Vec<&SpawnPoint>>();
+        let candidates = if free.is_empty() { allowed } else { free };
+        if enemies.is_empty() {
+            return candidates
+                .choose(&mut rand::thread_rng())
+                .map_or(fallback, |x| x.position);
+        }
+        let distance = |spawn: &SpawnPoint| {
+            enemies
+                .iter()
+                .map(|x| x.distance_to(spawn.position))
+                .fold(f32::MAX, f32::min)
+        };
+        candidates
+            .into_iter()
+            .max_by(|a, b| distance(a).total_cmp(&distance(b)))
+            .map_or(fallback, |x| x.position)
+    }
+
+    fn update_deaths(&mut self) {
+        let ids = self.players.keys().copied().collect::<Vec<u64>>();
+        for id in ids {
+            let Some(mut player) = self.players.remove(&id) else {
+                continue;
+            };
+            if player.alive() && player.health <= 0.0 {
+                player.die();
+                self.set_player_collision(&player, false);
+                if self.phase == MatchPhase::InProgress {
+                    let killer = player.last_attacker.and_then(|x| self.players.get(&x));
+                    self.mode.on_kill(&player, killer);
+                }
+                self.record(id, |x| x.deaths += 1);
+                let killer = player.last_attacker.filter(|x| *x != id);
+                if let Some(killer) = killer {
+                    self.record(killer, |x| x.kills += 1);
+                }
+                for assist in player.attackers.iter().filter(|x| Some(**x) != killer) {
+                    self.record(*assist, |x| x.assists += 1);
+                }
+            } else if player.state == PlayerState::Dead {
+                player.respawn_timer -= self.dt;
+                if player.respawn_timer <= 0.0 {
+                    player.respawn(self.select_spawn(id, player.team));
+                    self.update_collider(&mut player);
+                    self.set_player_collision(&player, true);
+                    self.query_pipeline.update(&self.colliders);
+                }
+            }
+            self.players.insert(id, player);
+        }
+    }
+    pub fn set_tick_rate(&mut self, tick_rate: u8) {
+        let tick_rate = tick_rate.clamp(MIN_TICK_RATE, MAX_TICK_RATE);
+        self.dt = 1.0 / tick_rate as f32;
+        self.integration_parameters.dt = self.dt;
+    }
+
+    fn queue_inputs(&mut self) {
+        while let Ok((id, input)) = self.receiver.try_recv() {
+            if !self.players.contains_key(&id) {
+                continue;
+            }
+            let queue = self.inputs.entry(id).or_default();
+            if queue.len() >= MAX_QUEUED_INPUTS {
+                queue.pop_front();
+            }
+            queue.push_back(input);
+        }
+    }
+
+    fn record_history(&mut self) {
+        let transforms = self
+            .players
+            .iter()
+            .filter_map(|(id, player)| {
+                let collider = self.colliders.get(player.collider)?;
+                Some((*id, *collider.position()))
+            })
+            .collect();
+        self.history.record(self.tick, transforms);
+    }
+
+    fn set_player_transforms(&mut self, transforms: &HashMap<u64, Isometry<Real>>) {
+        for (id, transform) in transforms.iter() {
+            let Some(player) = self.players.get(id) else {
+                continue;
+            };
+            if let Some(collider) = self.colliders.get_mut(player.collider) {
+                collider.set_position(*transform);
+            }
+            let head = transform.translation.vector + vector![0.0, player.head_offset, 0.0];
+            if let Some(collider) = self.colliders.get_mut(player.head) {
+                collider.set_translation(head);
+            }
+        }
+        self.query_pipeline.update(&self.colliders);
+    }
+
+    fn fire(&mut self, shooter: u64, view_tick: u32, weapon: &WeaponDef) {
+        let Some(player) = self.players.get(&shooter) else {
+            return;
+        };
+        let (origin, direction) = (player.position, apply_spread(player.fwd, weapon.spread));
+        let (entity, collider, head) = (player.entity, player.collider, player.head);
+        let max_rewind = (MAX_REWIND / self.dt) as u32;
+        let view_tick = if view_tick == 0 {
+            self.tick
+        } else {
+            view_tick.clamp(self.tick.saturating_sub(max_rewind), self.tick)
+        };
+        let rewound = if view_tick < self.tick {
+            self.history.at(view_tick).cloned()
+        } else {
+            None
+        };
+        let present = rewound.as_ref().map(|_| {
+            self.players
+                .iter()
+                .filter_map(|(id, x)| Some((*id, *self.colliders.get(x.collider)?.position())))
+                .collect::<HashMap<u64, Isometry<Real>>>()
+        });
+        if let Some(transforms) = rewound.as_ref() {
+            self.set_player_transforms(transforms);
+        }
+
+        let ray = Ray::new(
+            point![origin.x, origin.y, origin.z],
+            vector![direction.x, direction.y, direction.z],
+        );
+        let heads = self
+            .players
+            .values()
+            .filter(|x| x.id != shooter)
+            .map(|x| x.head)
+            .collect::<HashSet<ColliderHandle>>();
+        let not_head =
+            |handle: ColliderHandle, _: &Collider| handle != head && !heads.contains(&handle);
+        let is_head = |handle: ColliderHandle, _: &Collider| heads.contains(&handle);
+        let body_hit = self.query_pipeline.cast_ray(
+            &self.bodies,
+            &self.colliders,
+            &ray,
+            HITSCAN_RANGE,
+            true,
+            QueryFilter::default()
+                .exclude_collider(collider)
+                .predicate(&not_head),
+        );
+        let head_hit = self.query_pipeline.cast_ray(
+            &self.bodies,
+            &self.colliders,
+            &ray,
+            HITSCAN_RANGE,
+            true,
+            QueryFilter::default().predicate(&is_head),
+        );
+        let hit = match (head_hit, body_hit) {
+            (Some((handle, toi)), Some((body, body_toi))) => {
+                let own_body = self
+                    .players
+                    .values()
+                    .any(|x| x.head == handle && x.collider == body);
+                Some(if own_body || toi <= body_toi {
+                    (handle, toi)
+                } else {
+                    (body, body_toi)
+                })
+            }
+            (head_hit, body_hit) => head_hit.or(body_hit),
+        };
+        if let Some((handle, toi)) = hit {
+            let target = self
+                .players
+                .values()
+                .find(|x| x.collider == handle || x.head == handle)
+                .filter(|x| self.can_damage(shooter, x))
+                .map(|x| x.id);
+            if let Some(target) = target.and_then(|x| self.players.get_mut(&x)) {
+                let headshot = target.head == handle;
+                let damage = if headshot {
+                    weapon.damage * HEADSHOT_MULTIPLIER
+                } else {
+                    weapon.damage
+                };
+                let dealt = target.damage(damage, Some(shooter));
+                let point = ray.point_at(toi);
+                self.hits.push(HitEvent {
+                    shooter: entity,
+                    target: target.entity,
+                    point: [point.x, point.y, point.z],
+                    headshot,
+                });
+                self.record(shooter, |x| {
+                    x.hits += 1;
+                    x.damage += dealt;
+                });
+            }
+        }
+
+        if let Some(transforms) = present.as_ref() {
+            self.set_player_transforms(transforms);
+        }
+    }
+
+    pub fn tick(&mut self, pipeline: &mut PhysicsPipeline) {
+        self.tick = self.tick.wrapping_add(1).max(1);
+        self.queue_inputs();
+        let mut ids = self.players.keys().copied().collect::<Vec<u64>>();
+        ids.sort();
+        let gravity = Vector3::up() * PLAYER_GRAVITY;
+        let mut shots = vec![];
+        let mut launches = vec![];
+        for id in ids.iter() {
+            let Some(mut player) = self.players.remove(id) else {
+                continue;
+            };
+            let input = self.inputs.get_mut(id).and_then(|x| x.pop_front());
+            let previous = player.position;
+            player.simulate(input.as_ref(), self.dt, gravity);
+            if !player.alive() {
+                self.players.insert(*id, player);
+                continue;
+            }
+            for (slot, button) in [(0, INPUT_FIRE), (1, INPUT_ALT_FIRE)] {
+                let Some(weapon) = player.try_fire(input.as_ref(), slot, button) else {
+                    continue;
+                };
+                self.record(*id, |x| x.shots += 1);
+                match weapon.mode {
+                    FireMode::Hitscan => shots.push((
+                        *id,
+                        input.as_ref().map(|x| x.view_tick).unwrap_or(0),
+                        weapon,
+                    )),
+                    FireMode::Projectile => launches.push((*id, weapon)),
+                }
+            }
+            self.move_player(&mut player, previous);
+            self.players.insert(*id, player);
+        }
+        for (owner, weapon) in launches {
+            self.spawn_projectile(owner, &weapon);
+        }
+
+        let events =
+            ChannelEventCollector::new(self.collision_sender.clone(), self.contact_sender.clone());
         let rapier_gravity = vector![0.0, -90.81, 0.0];
         pipeline.step(
             &rapier_gravity,
@@ -115,8 +498,23 @@ impl GameManager {
             &mut self.ccd_solver,
             Some(&mut self.query_pipeline),
             &(),
-            &(),
+            &events,
         );
+        let (collisions, contacts) = (
+            self.collision_receiver.clone(),
+            self.contact_receiver.clone(),
+        );
+        custom_events::handle_collision(self, &collisions, &contacts);
+        self.update_projectiles();
+
+        for (shooter, view_tick, weapon) in shots {
+            self.fire(shooter, view_tick, &weapon);
+        }
+        self.update_deaths();
+        if self.phase == MatchPhase::InProgress {
+            self.mode.on_tick(&self.players, self.dt);
+        }
+        self.record_history();
 
         for object in self.network_objects.iter_mut() {
             for physics in self.objects.iter() {
@@ -129,21 +527,248 @@ impl GameManager {
                 }
             }
         }
-        while !self.receiver.is_empty() {
-            let mut player = self.receiver.recv().unwrap();
-            self.update_player(&mut player);
+
+        let status =
+            self.mode
+                .status()
+                .with_phase(self.phase, self.phase_time, &self.scene, self.bounds);
+        let status = (self.match_status.as_ref() != Some(&status)).then_some(status);
+        if status.is_some() {
+            self.match_status = status.clone();
+        }
+        for id in ids.iter() {
+            self.send_update(*id, status.as_ref());
+        }
+        self.hits.clear();
+        self.removed.clear();
+        self.left.clear();
+    }
+
+    fn spawn_projectile(&mut self, owner: u64, weapon: &WeaponDef) {
+        let Some(player) = self.players.get(&owner) else {
+            return;
+        };
+        let direction = apply_spread(player.fwd, weapon.spread);
+        let position = player.position + direction * PROJECTILE_SPAWN_OFFSET;
+        let velocity = direction * weapon.speed;
+        let id = self.register_entity(EntityKind::Projectile, "Projectile");
+        if let Some(entry) = self.entities.values_mut().next_back() {
+            entry.lifetime = PROJECTILE_LIFETIME;
         }
-        *instant = Instant::now();
+        let object = NetworkObject::new(id, position, Vector4::new(0.0, 0.0, 0.0, 1.0));
+        let mut collider =
+            create_collider(&S::SPHERE(Sphere::new(PROJECTILE_RADIUS)), 0.0, 1.0, None);
+        collider.set_active_events(ActiveEvents::COLLISION_EVENTS);
+        let mut body = create_body(RigidBodyType::Dynamic, position, 0.0, 0.0);
+        body.set_linvel(vector![velocity.x, velocity.y, velocity.z], true);
+        let body_handle = self.bodies.insert(body);
+        let collider_handle =
+            self.colliders
+                .insert_with_parent(collider, body_handle, &mut self.bodies);
+        self.network_objects.push(object.clone());
+        self.projectiles.push(Projectile::new(
+            object,
+            owner,
+            collider_handle,
+            body_handle,
+            weapon.damage,
+        ));
     }
 
-    pub fn update_player(&mut self, player: &mut Player) {
-        self.move_player(player);
-        self.players.insert(player.id, player.clone());
-        let signal = self.build_signal(player);
-        self.nsender.send((player.to_owned(), signal)).unwrap();
+    pub fn projectile_impact(&mut self, collider: ColliderHandle) {
+        if let Some(index) = self.projectiles.iter().position(|x| x.collider == collider) {
+            self.explode(index);
+        }
     }
 
-    fn build_signal(&mut self, player: &mut Player) -> ResponseSignal {
+    fn explode(&mut self, index: usize) {
+        let projectile = self.projectiles.remove(index);
+        let center = self
+            .bodies
+            .get(projectile.body)
+            .map(|x| *x.translation())
+            .unwrap_or_else(|| {
+                let [x, y, z] = projectile.object.position;
+                vector![x, y, z]
+            });
+        let (x, y, z) = (center.x, center.y, center.z);
+        for (_, _, handle) in self.objects.iter() {
+            let Some(body) = self.bodies.get_mut(*handle) else {
+                continue;
+            };
+            if !body.is_dynamic() {
+                continue;
+            }
+            let offset = body.translation() - center;
+            let strength = falloff(offset.norm());
+            if strength <= 0.0 {
+                continue;
+            }
+            let direction = offset
+                .try_normalize(f32::EPSILON)
+                .unwrap_or(vector![0.0, 1.0, 0.0]);
+            body.apply_impulse(direction * EXPLOSION_IMPULSE * strength, true);
+        }
+        let targets = self
+            .players
+            .values()
+            .filter(|x| self.can_damage(projectile.owner, x))
+            .map(|x| x.id)
+            .collect::<Vec<u64>>();
+        let mut total = 0.0;
+        for id in targets {
+            let Some(player) = self.players.get_mut(&id) else {
+                continue;
+            };
+            let distance = player.position.distance_to(Vector3::new(x, y, z));
+            let strength = falloff(distance);
+            if strength <= 0.0 {
+                continue;
+            }
+            let dealt = player.damage(projectile.damage * strength, Some(projectile.owner));
+            if id != projectile.owner {
+                total += dealt;
+            }
+        }
+        if total > 0.0 {
+            self.record(projectile.owner, |x| {
+                x.hits += 1;
+                x.damage += total;
+            });
+        }
+        self.despawn_projectile(projectile);
+    }
+
+    fn despawn_projectile(&mut self, projectile: Projectile) {
+        self.bodies.remove(
+            projectile.body,
+            &mut self.island_manager,
+            &mut self.colliders,
+            &mut self.impulse_joint_set,
+            &mut self.multibody_joint_set,
+            true,
+        );
+        self.network_objects
+            .retain(|x| x.id != projectile.object.id);
+        self.removed.push(projectile.object.id);
+        self.release_entity(projectile.object.id);
+    }
+
+    fn update_projectiles(&mut self) {
+        for projectile in self.projectiles.iter_mut() {
+            projectile.lifetime -= self.dt;
+            if let Some(body) = self.bodies.get(projectile.body) {
+                let position = body.translation();
+                projectile.object.position = [position.x, position.y, position.z];
+            }
+            let id = projectile.object.id;
+            if let Some(object) = self.network_objects.iter_mut().find(|x| x.id == id) {
+                object.position = projectile.object.position;
+            }
+        }
+        while let Some(index) = self.projectiles.iter().position(|x| x.lifetime <= 0.0) {
+            self.explode(index);
+        }
+    }
+
+    fn send_update(&mut self, id: u64, status: Option<&MatchStatus>) {
+        let latest = self.snapshots.get(&id).map_or(0, |x| x.latest());
+        let Some(player) = self.players.get_mut(&id) else {
+            return;
+        };
+        let (version, ack) = (player.version, player.ack);
+        let scoreboard = std::mem::take(&mut player.scoreboard_requested);
+        let table = EntityTable::new(
+            self.entities
+                .range(player.known_entities..)
+                .map(|(_, x)| x)
+                .filter(|x| player.version >= 2 || x.kind != EntityKind::Projectile)
+                .cloned()
+                .collect(),
+        );
+        player.known_entities = self.entity_serial;
+        let encoding = if player.capabilities & CAP_QUANTIZED != 0 {
+            self.encoding
+        } else {
+            SnapshotEncoding::Full
+        };
+        let status = status.filter(|_| version >= 2).cloned();
+        if ack != 0 {
+            let ping = latest.wrapping_sub(ack) as f32 * self.dt * 1000.0;
+            if let Some(stats) = self.stats.get_mut(&id) {
+                stats.sample_ping(ping);
+            }
+        }
+        let scoreboard = (scoreboard && version >= 2).then(|| self.scoreboard());
+        let left = if version >= 2 {
+            self.left.clone()
+        } else {
+            Vec::new()
+        };
+        let signal = match self.build_signal(id) {
+            Ok(signal) => signal,
+            Err(err) => {
+                println!("failed to build update for {}: {}", id, err);
+                return;
+            }
+        };
+        let snapshot = Snapshot::new(signal, encoding, &self.bounds);
+        if let Some(outbound) = self.outbound.get(&id) {
+            let _ = outbound.send(PlayerUpdate {
+                snapshot,
+                table,
+                status,
+                scoreboard,
+                left,
+            });
+        }
+    }
+
+    pub fn player_count(&self) -> usize {
+        self.players.values().filter(|x| !x.spectating()).count()
+    }
+
+    pub fn scoreboard(&self) -> Scoreboard {
+        Scoreboard::new(
+            self.players
+                .keys()
+                .filter_map(|x| self.stats.get(x))
+                .map(ScoreEntry::new)
+                .collect(),
+        )
+    }
+
+    pub fn end_match(&mut self) {
+        for player in self.players.values_mut() {
+            player.scoreboard_requested = true;
+        }
+    }
+
+    pub fn summary(&self, session: &str) -> MatchSummary {
+        let mut players = self
+            .stats
+            .iter()
+            .map(|(id, x)| PlayerSummary {
+                player: *id,
+                stats: x.clone(),
+                accuracy: x.accuracy(),
+            })
+            .collect::<Vec<PlayerSummary>>();
+        players.sort_by(|a, b| b.stats.kills.cmp(&a.stats.kills));
+        MatchSummary {
+            session: session.to_string(),
+            map: self.scene.clone(),
+            mode: format!("{:?}", self.mode.kind()),
+            winner: format!("{:?}", self.mode.winner()),
+            ended_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |x| x.as_secs()),
+            players,
+        }
+    }
+
+    fn build_signal(&mut self, id: u64) -> Result<ResponseSignal, ServerError> {
+        let player = &self.players[&id];
         let mut signal = ResponseSignal::new(
             player.position,
             player.camera_position,
@@ -151,27 +776,59 @@ impl GameManager {
             player.fwd,
             player.right,
         );
-        signal.objects = self.network_objects.clone();
-        signal.players = self
+        signal.id = player.entity;
+        signal.last_input = player.last_input;
+        signal.health = player.health.ceil() as u8;
+        signal.armour = player.armour.ceil() as u8;
+        signal.state = player.state;
+        signal.team = player.team.unwrap_or(TEAM_NONE);
+        signal.tick = self.tick;
+        signal.hits = self.hits.clone();
+        signal.removed = self.removed.clone();
+        let ack = player.ack;
+        let players = self
             .players
             .values()
+            .filter(|x| !x.spectating())
             .map(|x| {
-                ResponseSignal::new(
+                let mut state = ResponseSignal::new(
                     x.position,
                     x.camera_position,
                     x.camera_target,
                     x.fwd,
                     x.right,
-                )
+                );
+                state.id = x.entity;
+                state.health = x.health.ceil() as u8;
+                state.armour = x.armour.ceil() as u8;
+                state.state = x.state;
+                state.team = x.team.unwrap_or(TEAM_NONE);
+                state
             })
             .collect::<Vec<ResponseSignal>>();
-        signal.update().unwrap();
-        signal
+        let state = WorldState::new(&self.network_objects, &players);
+        let history = self.snapshots.entry(id).or_default();
+        let baseline = history.baseline(ack);
+        let (objects, players, removed) = state.delta(baseline.map(|x| &x.1));
+        for id in removed {
+            if !signal.removed.contains(&id) {
+                signal.removed.push(id);
+            }
+        }
+        signal.removed.truncate(u8::MAX as usize);
+        signal.baseline = baseline.map(|x| x.0).unwrap_or(0);
+        signal.objects = objects;
+        signal.players = players;
+        signal.snapshot = history.push(state);
+        signal.update()?;
+        Ok(signal)
     }
 
     pub fn new() -> Self {
         let (sender, receiver) = unbounded();
-        let (nsender, nreceiver) = unbounded();
+        let (leave_sender, leave_receiver) = unbounded();
+        let (collision_sender, collision_receiver) = unbounded();
+        let (contact_sender, contact_receiver) = unbounded();
         Self {
             colliders: ColliderSet::new(),
             bodies: RigidBodySet::new(),
@@ -187,16 +844,71 @@ impl GameManager {
             _event_handler: None,
             objects: Vec::new(),
             players: HashMap::new(),
+            snapshots: HashMap::new(),
             dt: 0.016,
             network_objects: Vec::new(),
             default_player: None,
+            spawn_points: Vec::new(),
+            flag_bases: Vec::new(),
+            mode: Box::new(Deathmatch::new()),
+            phase: MatchPhase::Warmup,
+            phase_time: 0.0,
+            scene: String::new(),
+            friendly_fire: false,
+            stats: HashMap::new(),
+            match_status: None,
+            loadout: Vec::new(),
             sender,
             receiver,
-            nsender,
-            nreceiver,
+            leave_sender,
+            leave_receiver,
+            outbound: HashMap::new(),
+            collision_sender,
+            collision_receiver,
+            contact_sender,
+            contact_receiver,
+            inputs: HashMap::new(),
+            entities: BTreeMap::new(),
+            encoding: SnapshotEncoding::Full,
+            bounds: Bounds::default(),
+            tick: 0,
+            history: ColliderHistory::default(),
+            hits: Vec::new(),
+            projectiles: Vec::new(),
+            removed: Vec::new(),
+            left: Vec::new(),
+            next_entity: 0,
+            entity_serial: 0,
+            free_entities: VecDeque::new(),
         }
     }
 
+    fn register_entity(&mut self, kind: EntityKind, name: &str) -> EntityId {
+        let id = match self.free_entities.front() {
+            Some(&(freed, id))
+                if self.tick.wrapping_sub(freed) > ENTITY_REUSE_DELAY
+                    || self.next_entity == EntityId::MAX =>
+            {
+                self.free_entities.pop_front();
+                id
+            }
+            _ => {
+                let id = self.next_entity;
+                self.next_entity = self.next_entity.wrapping_add(1);
+                id
+            }
+        };
+        self.entities
+            .insert(self.entity_serial, EntityEntry::new(id, kind, name));
+        self.entity_serial += 1;
+        id
+    }
+
+    fn release_entity(&mut self, id: EntityId) {
+        self.entities.retain(|_, x| x.id != id);
+        self.free_entities.push_back((self.tick, id));
+    }
+
     pub fn add_object(
         &mut self,
         position: Vector3,
@@ -210,12 +922,16 @@ impl GameManager {
         additional_mass: f32,
         density: f32,
     ) {
+        let id = self.register_entity(EntityKind::Object, &name);
         let object = NetworkObject::new(
-            name,
+            id,
             position,
             Vector4::new(rotation.x, rotation.y, rotation.z, 1.0),
         );
         let mut collider = create_collider(&shape, restitution, density, Some(vertices));
+        if let Some(team) = team_only(&name) {
+            collider.set_collision_groups(team_only_groups(team, collider.is_sensor()));
+        }
         collider.set_rotation(UnitQuaternion::new(vector![
             rotation.x, rotation.y, rotation.z
         ]));
@@ -239,24 +955,117 @@ impl GameManager {
         );
     }
 
-    pub fn remove_player(&mut self, player_id: &u64) {
+    pub fn remove_player(&mut self, player_id: &u64, reason: LeaveReason) {
         println!("REMOVING ID {}", player_id);
-        let player = self.players.remove(player_id).unwrap();
-        self.colliders.remove(
-            player.collider,
-            &mut self.island_manager,
-            &mut self.bodies,
-            false,
-        );
+        let Some(player) = self.players.remove(player_id) else {
+            return;
+        };
+        if !player.spectating() {
+            self.mode.on_leave(&player);
+            self.match_status = None;
+            self.removed.push(player.entity);
+            self.left.push(PlayerLeft {
+                id: player.entity,
+                reason,
+            });
+        }
+        self.release_entity(player.entity);
+        self.snapshots.remove(player_id);
+        self.outbound.remove(player_id);
+        self.inputs.remove(player_id);
+        for handle in [player.collider, player.head] {
+            self.colliders
+                .remove(handle, &mut self.island_manager, &mut self.bodies, false);
+        }
     }
 
-    pub fn init_scene(&mut self, scene_path: &str) {
-        let objects = load_scene(scene_path);
+    pub fn restart_match(&mut self) {
+        self.mode = self.mode.kind().create(&self.flag_bases);
+        self.match_status = None;
+        let mut ids = self.players.keys().copied().collect::<Vec<u64>>();
+        ids.sort();
+        for id in ids {
+            let Some(mut player) = self.players.remove(&id) else {
+                continue;
+            };
+            if player.spectating() {
+                self.players.insert(id, player);
+                continue;
+            }
+            self.mode.on_join(&mut player, &self.players);
+            player.respawn(self.select_spawn(id, player.team));
+            self.update_collider(&mut player);
+            self.set_player_team(&player);
+            self.set_player_collision(&player, true);
+            self.query_pipeline.update(&self.colliders);
+            self.players.insert(id, player);
+        }
+        self.reset_stats();
+    }
+
+    pub fn adopt(&mut self, previous: GameManager) {
+        self.sender = previous.sender;
+        self.receiver = previous.receiver;
+        self.leave_sender = previous.leave_sender;
+        self.leave_receiver = previous.leave_receiver;
+        self.encoding = previous.encoding;
+        self.friendly_fire = previous.friendly_fire;
+        self.dt = previous.dt;
+        self.integration_parameters.dt = previous.dt;
+        self.mode = previous.mode.kind().create(&self.flag_bases);
+        let mut ids = previous.players.keys().copied().collect::<Vec<u64>>();
+        ids.sort();
+        for id in ids {
+            let (Some(player), Some(outbound)) =
+                (previous.players.get(&id), previous.outbound.get(&id))
+            else {
+                continue;
+            };
+            let mut admitted = self.admit_player(
+                id,
+                player.version,
+                player.capabilities,
+                player.team_choice,
+                player.spectating(),
+                outbound.clone(),
+            );
+            admitted.last_input = player.last_input;
+            self.players.insert(id, admitted);
+        }
+    }
+
+    pub fn init_scene(&mut self, scene_path: &str) -> Result<(), ServerError> {
+        let objects = load_scene(scene_path)?;
+        if !objects.iter().any(|x| x.name.get(2..) == Some("Player")) {
+            return Err(ServerError::Scene(format!("{} has no player", scene_path)));
+        }
+        self.scene = scene_path.to_string();
+        let mut bounds = Bounds::new([f32::MAX; 3], [f32::MIN; 3]);
+        for object in objects.iter() {
+            for vertex in object.vertices.iter() {
+                bounds.include([
+                    object.position.x + vertex[0],
+                    object.position.y + vertex[1],
+                    object.position.z + vertex[2],
+                ]);
+            }
+        }
+        if bounds.min[0] <= bounds.max[0] {
+            self.bounds = bounds.padded(BOUNDS_MARGIN);
+        }
         for object in objects {
+            if let Some(spawn) = spawn_point(&object) {
+                self.spawn_points.push(spawn);
+                continue;
+            }
+            if let Some(flag) = flag_base(&object) {
+                self.flag_bases.push(flag);
+                continue;
+            }
             match &object.name[2..] {
                 "Player" => {
                     let player = self.create_player(
-                        1.0,
+                        10.0,
                         object.position,
                         0.0,
                         1.0,
@@ -292,6 +1101,7 @@ impl GameManager {
                 ),
             }
         }
+        Ok(())
     }
     fn create_player(
         &mut self,
@@ -319,21 +1129,172 @@ impl GameManager {
             cam_controller,
         );
         player.vertices = Some(vertices);
+        player.fit_head();
         self.colliders
             .remove(col, &mut self.island_manager, &mut self.bodies, false);
         player
     }
-    pub fn new_player(&mut self) -> Player {
+    pub fn new_player(
+        &mut self,
+        version: u16,
+        capabilities: u32,
+        team_choice: Option<u8>,
+        spectator: bool,
+    ) -> (Player, UnboundedReceiver<PlayerUpdate>) {
         let mut rng = rand::thread_rng();
         let id = rng.gen_range(0..std::u64::MAX);
+        let (outbound, updates) = unbounded_channel();
+        (
+            self.admit_player(id, version, capabilities, team_choice, spectator, outbound),
+            updates,
+        )
+    }
+
+    fn admit_player(
+        &mut self,
+        id: u64,
+        version: u16,
+        capabilities: u32,
+        team_choice: Option<u8>,
+        spectator: bool,
+        outbound: UnboundedSender<PlayerUpdate>,
+    ) -> Player {
         let mut player = self.default_player.as_mut().unwrap().clone();
         player.camera_controller = KinematicCharacterController::default();
         player.obj = KinematicCharacterController::default();
         let collider = create_collider(&S::CONVEX, 0.0, 1.0, player.clone().vertices);
         let collider_handle = self.colliders.insert(collider);
         player.collider = collider_handle;
+        let mut head = create_collider(&S::SPHERE(Sphere::new(player.head_radius)), 0.0, 1.0, None);
+        head.set_sensor(true);
+        player.head = self.colliders.insert(head);
         player.id = id;
+        player.entity = self.register_entity(EntityKind::Player, "Player");
+        player.ack = 0;
+        player.last_input = 0;
+        player.known_entities = 0;
+        player.version = version;
+        player.capabilities = capabilities;
+        player.team_choice = team_choice;
+        player.equip(&self.loadout);
+        if spectator {
+            player.state = PlayerState::Spectating;
+            self.set_player_collision(&player, false);
+        } else {
+            self.mode.on_join(&mut player, &self.players);
+            self.match_status = None;
+            player.respawn(self.select_spawn(id, player.team));
+            self.update_collider(&mut player);
+            self.set_player_team(&player);
+            self.stats
+                .insert(id, PlayerStats::new(player.entity, player.team));
+        }
+        self.query_pipeline.update(&self.colliders);
         self.players.insert(id, player.clone());
+        self.snapshots.insert(id, SnapshotHistory::default());
+        self.outbound.insert(id, outbound);
         player
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn released_entities_are_dropped_and_recycled_after_delay() {
+        let mut manager = GameManager::new();
+        let first = manager.register_entity(EntityKind::Projectile, "Projectile");
+        manager.release_entity(first);
+        assert!(manager.entities.values().all(|x| x.id != first));
+        let second = manager.register_entity(EntityKind::Projectile, "Projectile");
+        assert_ne!(second, first);
+        manager.tick = ENTITY_REUSE_DELAY + 1;
+        let third = manager.register_entity(EntityKind::Projectile, "Projectile");
+        assert_eq!(third, first);
+        let live = manager.entities.values().map(|x| x.id).collect::<Vec<_>>();
+        assert_eq!(live, vec![second, third]);
+    }
+
+    fn place(manager: &mut GameManager, id: u64, position: Vector3) {
+        let mut player = manager.players.remove(&id).unwrap();
+        player.position = position;
+        manager.update_collider(&mut player);
+        manager.players.insert(id, player);
+        manager.query_pipeline.update(&manager.colliders);
+    }
+
+    fn shoot(manager: &mut GameManager, shooter: u64, target: Vector3) -> bool {
+        let weapon = WeaponDef {
+            name: "test".into(),
+            mode: FireMode::Hitscan,
+            damage: 1.0,
+            fire_rate: 1.0,
+            spread: 0.0,
+            magazine: 1,
+            reload_time: 1.0,
+            speed: 0.0,
+        };
+        let player = manager.players.get_mut(&shooter).unwrap();
+        player.fwd = (target - player.position).normalized();
+        let hits = manager.hits.len();
+        manager.fire(shooter, 0, &weapon);
+        assert_eq!(manager.hits.len(), hits + 1);
+        manager.hits.last().unwrap().headshot
+    }
+
+    #[test]
+    fn rays_at_the_head_report_headshots() {
+        let mut manager = GameManager::new();
+        manager.init_scene(MAP_ROTATION[0]).unwrap();
+        let (shooter, _) = manager.new_player(PROTOCOL_VERSION, 0, None, false);
+        let (target, _) = manager.new_player(PROTOCOL_VERSION, 0, None, false);
+        let origin = Vector3::new(0.0, 1000.0, 0.0);
+        place(&mut manager, shooter.id, origin);
+        place(
+            &mut manager,
+            target.id,
+            origin + Vector3::new(0.0, 0.0, 100.0),
+        );
+
+        let target = &manager.players[&target.id];
+        let (head, body) = (target.head_position(), target.position);
+        assert!(target.head_radius > HEAD_RADIUS);
+        assert!(shoot(&mut manager, shooter.id, head));
+        assert!(!shoot(&mut manager, shooter.id, body));
+    }
+
+    #[test]
+    fn players_admitted_together_spawn_apart() {
+        let mut manager = GameManager::new();
+        manager.init_scene(MAP_ROTATION[0]).unwrap();
+        let positions = (0..4)
+            .map(|_| {
+                manager
+                    .new_player(PROTOCOL_VERSION, 0, None, false)
+                    .0
+                    .position
+            })
+            .collect::<Vec<Vector3>>();
+        for (index, position) in positions.iter().enumerate() {
+            assert!(manager.spawn_points.iter().any(|x| x.position == *position));
+            assert!(positions[..index].iter().all(|x| x != position));
+        }
+    }
+
+    #[test]
+    fn rotation_scenes_load_and_bad_scenes_are_rejected() {
+        for scene in MAP_ROTATION {
+            let mut manager = GameManager::new();
+            assert!(manager.init_scene(scene).is_ok());
+            assert!(manager.default_player.is_some());
+            assert!(ModeKind::CaptureTheFlag.supported(&manager.flag_bases));
+        }
+        assert!(!ModeKind::CaptureTheFlag.supported(&[]));
+        let mut manager = GameManager::new();
+        assert!(manager.init_scene("static/models/space.obj").is_err());
+        assert!(manager.init_scene("static/models/missing.obj").is_err());
+        assert!(manager.default_player.is_none());
+        assert!(manager.scene.is_empty());
+    }
+}
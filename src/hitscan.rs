@@ -0,0 +1,40 @@
+use crate::*;
+use std::collections::{HashMap, VecDeque};
+
+pub const HISTORY_TICKS: usize = 128;
+pub const MAX_REWIND: f32 = 0.5;
+pub const HITSCAN_RANGE: f32 = 500.0;
+pub const HEADSHOT_MULTIPLIER: f32 = 2.0;
+
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+#[deku(ctx = "version: u16", ctx_default = "PROTOCOL_VERSION")]
+pub struct HitEvent {
+    pub shooter: EntityId,
+    pub target: EntityId,
+    pub point: [f32; 3],
+    #[deku(cond = "version >= 2", default = "false")]
+    pub headshot: bool,
+}
+
+#[derive(Clone, Default)]
+pub struct ColliderHistory {
+    ticks: VecDeque<(u32, HashMap<u64, Isometry<Real>>)>,
+}
+
+impl ColliderHistory {
+    pub fn record(&mut self, tick: u32, transforms: HashMap<u64, Isometry<Real>>) {
+        if self.ticks.len() >= HISTORY_TICKS {
+            self.ticks.pop_front();
+        }
+        self.ticks.push_back((tick, transforms));
+    }
+
+    pub fn at(&self, tick: u32) -> Option<&HashMap<u64, Isometry<Real>>> {
+        self.ticks
+            .iter()
+            .rev()
+            .find(|x| x.0 <= tick)
+            .or(self.ticks.front())
+            .map(|x| &x.1)
+    }
+}
@@ -1,24 +1,44 @@
+use auth::*;
+use codec::*;
 use crossbeam::channel::{unbounded, Receiver, Sender};
 use deku::prelude::*;
+use error::*;
 use game::GameManager;
+use hitscan::*;
+use mode::*;
 use network::*;
-use objects::{Shape as S, Sphere};
+use objects::{EntityEntry, EntityId, EntityKind, EntityTable, NetworkObject, Shape as S, Sphere};
 use player::*;
+use projectile::*;
+use quantize::*;
 use rand::prelude::*;
 use rapier3d::prelude::*;
 use raylib::{math::Vector3, shaders::RaylibShader};
 use session::*;
+use snapshot::*;
+use stats::*;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use weapon::*;
 
+pub mod auth;
+pub mod codec;
 pub mod custom_events;
+pub mod error;
 pub mod game;
+pub mod hitscan;
 pub mod lights;
+pub mod mode;
 pub mod network;
 pub mod objects;
 pub mod player;
+pub mod projectile;
+pub mod quantize;
 pub mod reader;
 pub mod session;
+pub mod snapshot;
+pub mod stats;
+pub mod weapon;
 
 #[derive(Debug, DekuRead, DekuWrite)]
 struct Test {
@@ -27,9 +47,21 @@ struct Test {
 
 #[tokio::main]
 async fn main() {
-    let mut network = GameNetwork::new("127.0.0.1:9001".into());
-    network.start().await;
+    let mut network = match GameNetwork::new("127.0.0.1:9001".into()) {
+        Ok(network) => network,
+        Err(err) => {
+            println!("failed to load game data: {}", err);
+            return;
+        }
+    };
+    if let Err(err) = network.start().await {
+        println!("failed to start server: {}", err);
+        return;
+    }
     loop {
-        network.update().await;
+        if let Err(err) = network.update().await {
+            println!("server stopped: {}", err);
+            break;
+        }
     }
 }
@@ -0,0 +1,558 @@
+use crate::objects::EntityId;
+use crate::reader::FlagBase;
+use crate::*;
+use std::collections::HashMap;
+
+pub const TEAM_COUNT: u8 = 2;
+pub const TEAM_NONE: u8 = 0xFF;
+pub const DEATHMATCH_SCORE_LIMIT: i16 = 25;
+pub const TEAM_DEATHMATCH_SCORE_LIMIT: i16 = 50;
+pub const CAPTURE_LIMIT: i16 = 3;
+pub const MATCH_TIME: f32 = 600.0;
+pub const FLAG_RADIUS: f32 = 2.5;
+pub const FLAG_RETURN_TIME: f32 = 30.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(type = "u8")]
+pub enum ModeKind {
+    #[deku(id = "0x0")]
+    Deathmatch,
+    #[deku(id = "0x1")]
+    TeamDeathmatch,
+    #[deku(id = "0x2")]
+    CaptureTheFlag,
+}
+
+impl ModeKind {
+    pub fn create(&self, flag_bases: &[FlagBase]) -> Box<dyn GameMode> {
+        match self {
+            ModeKind::Deathmatch => Box::new(Deathmatch::new()),
+            ModeKind::TeamDeathmatch => Box::new(TeamDeathmatch::new()),
+            ModeKind::CaptureTheFlag => Box::new(CaptureTheFlag::new(flag_bases)),
+        }
+    }
+
+    pub fn supported(&self, flag_bases: &[FlagBase]) -> bool {
+        match self {
+            ModeKind::CaptureTheFlag => (0..TEAM_COUNT)
+                .all(|team| flag_bases.iter().filter(|x| x.team == team).count() == 1),
+            _ => true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(type = "u8")]
+pub enum MatchPhase {
+    #[deku(id = "0x0")]
+    Warmup,
+    #[deku(id = "0x1")]
+    Countdown,
+    #[deku(id = "0x2")]
+    InProgress,
+    #[deku(id = "0x3")]
+    Intermission,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(type = "u8")]
+pub enum Winner {
+    #[deku(id = "0x0")]
+    Undecided,
+    #[deku(id = "0x1")]
+    Player(EntityId),
+    #[deku(id = "0x2")]
+    Team(u8),
+    #[deku(id = "0x3")]
+    Draw,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
+pub struct Score {
+    pub id: u16,
+    pub score: i16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(type = "u8")]
+pub enum FlagState {
+    #[deku(id = "0x0")]
+    AtBase,
+    #[deku(id = "0x1")]
+    Carried,
+    #[deku(id = "0x2")]
+    Dropped,
+}
+
+#[derive(Clone, Debug, PartialEq, DekuRead, DekuWrite)]
+pub struct FlagStatus {
+    pub team: u8,
+    pub state: FlagState,
+    pub carrier: EntityId,
+    pub position: [f32; 3],
+}
+
+#[derive(Clone, Debug, PartialEq, DekuRead, DekuWrite)]
+#[deku(ctx = "version: u16", ctx_default = "PROTOCOL_VERSION")]
+pub struct MatchStatus {
+    pub mode: ModeKind,
+    #[deku(cond = "version >= 2", default = "MatchPhase::InProgress")]
+    pub phase: MatchPhase,
+    #[deku(cond = "version >= 2", default = "0")]
+    pub phase_time: u16,
+    #[deku(cond = "version >= 2", default = "0", update = "self.map.len()")]
+    map_len: u8,
+    #[deku(cond = "version >= 2", count = "map_len", default = "Vec::new()")]
+    pub map: Vec<u8>,
+    #[deku(cond = "version >= 2", default = "Bounds::default()")]
+    pub bounds: Bounds,
+    pub time_left: u16,
+    #[deku(update = "self.scores.len()")]
+    score_count: u8,
+    #[deku(count = "score_count")]
+    pub scores: Vec<Score>,
+    #[deku(update = "self.flags.len()")]
+    flag_count: u8,
+    #[deku(count = "flag_count")]
+    pub flags: Vec<FlagStatus>,
+    pub winner: Winner,
+}
+
+impl MatchStatus {
+    pub fn new(
+        mode: ModeKind,
+        time_left: f32,
+        scores: Vec<Score>,
+        flags: Vec<FlagStatus>,
+        winner: Winner,
+    ) -> Self {
+        Self {
+            mode,
+            phase: MatchPhase::InProgress,
+            phase_time: 0,
+            map_len: 0,
+            map: Vec::new(),
+            bounds: Bounds::default(),
+            time_left: time_left.ceil().max(0.0) as u16,
+            score_count: scores.len() as u8,
+            scores,
+            flag_count: flags.len() as u8,
+            flags,
+            winner,
+        }
+    }
+
+    pub fn with_phase(
+        mut self,
+        phase: MatchPhase,
+        phase_time: f32,
+        map: &str,
+        bounds: Bounds,
+    ) -> Self {
+        let map = map.as_bytes()[..map.len().min(u8::MAX as usize)].to_vec();
+        self.phase = phase;
+        self.phase_time = phase_time.ceil().max(0.0) as u16;
+        self.map_len = map.len() as u8;
+        self.map = map;
+        self.bounds = bounds;
+        self
+    }
+}
+
+pub trait GameMode: Send {
+    fn kind(&self) -> ModeKind;
+    fn on_join(&mut self, player: &mut Player, players: &HashMap<u64, Player>);
+    fn on_leave(&mut self, player: &Player);
+    fn on_kill(&mut self, victim: &Player, killer: Option<&Player>);
+    fn on_tick(&mut self, players: &HashMap<u64, Player>, dt: f32);
+    fn winner(&self) -> Winner;
+    fn status(&self) -> MatchStatus;
+    fn clone_box(&self) -> Box<dyn GameMode>;
+}
+
+impl Clone for Box<dyn GameMode> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+fn team_size(players: &HashMap<u64, Player>, team: u8) -> usize {
+    players.values().filter(|x| x.team == Some(team)).count()
+}
+
+fn balance_team(choice: Option<u8>, players: &HashMap<u64, Player>) -> u8 {
+    let smallest = (0..TEAM_COUNT)
+        .min_by_key(|team| team_size(players, *team))
+        .unwrap_or(0);
+    match choice {
+        Some(team)
+            if team < TEAM_COUNT && team_size(players, team) <= team_size(players, smallest) =>
+        {
+            team
+        }
+        _ => smallest,
+    }
+}
+
+fn team_group(team: u8) -> Group {
+    Group::from_bits_truncate(Group::GROUP_2.bits() << team.min(TEAM_COUNT - 1))
+}
+
+pub fn team_groups(team: Option<u8>) -> InteractionGroups {
+    match team {
+        Some(team) => InteractionGroups::new(team_group(team), Group::ALL),
+        None => InteractionGroups::new(Group::GROUP_1, Group::ALL),
+    }
+}
+
+pub fn team_only_groups(team: u8, sensor: bool) -> InteractionGroups {
+    let filter = if sensor {
+        team_group(team)
+    } else {
+        Group::ALL ^ team_group(team)
+    };
+    InteractionGroups::new(Group::GROUP_1, filter)
+}
+
+fn leader<K: Copy>(scores: &HashMap<K, i16>) -> Option<(K, i16)> {
+    let (id, best) = scores.iter().max_by_key(|x| *x.1)?;
+    let tied = scores.values().filter(|x| *x == best).count() > 1;
+    (!tied).then_some((*id, *best))
+}
+
+fn team_scores(scores: &HashMap<u8, i16>) -> Vec<Score> {
+    let mut scores = scores
+        .iter()
+        .map(|(id, score)| Score {
+            id: *id as u16,
+            score: *score,
+        })
+        .collect::<Vec<Score>>();
+    scores.sort_by_key(|x| x.id);
+    scores
+}
+
+fn decide(scores: &HashMap<u8, i16>, limit: i16, time_left: f32) -> Winner {
+    match leader(scores) {
+        Some((team, score)) if score >= limit || time_left <= 0.0 => Winner::Team(team),
+        None if time_left <= 0.0 => Winner::Draw,
+        _ => Winner::Undecided,
+    }
+}
+
+#[derive(Clone)]
+pub struct Deathmatch {
+    scores: HashMap<EntityId, i16>,
+    time_left: f32,
+    winner: Winner,
+}
+
+impl Deathmatch {
+    pub fn new() -> Self {
+        Self {
+            scores: HashMap::new(),
+            time_left: MATCH_TIME,
+            winner: Winner::Undecided,
+        }
+    }
+}
+
+impl GameMode for Deathmatch {
+    fn kind(&self) -> ModeKind {
+        ModeKind::Deathmatch
+    }
+
+    fn on_join(&mut self, player: &mut Player, _players: &HashMap<u64, Player>) {
+        player.team = None;
+        self.scores.insert(player.entity, 0);
+    }
+
+    fn on_leave(&mut self, player: &Player) {
+        self.scores.remove(&player.entity);
+    }
+
+    fn on_kill(&mut self, victim: &Player, killer: Option<&Player>) {
+        if self.winner != Winner::Undecided {
+            return;
+        }
+        match killer {
+            Some(killer) if killer.id != victim.id => {
+                *self.scores.entry(killer.entity).or_default() += 1;
+            }
+            _ => *self.scores.entry(victim.entity).or_default() -= 1,
+        }
+    }
+
+    fn on_tick(&mut self, _players: &HashMap<u64, Player>, dt: f32) {
+        if self.winner != Winner::Undecided {
+            return;
+        }
+        self.time_left = (self.time_left - dt).max(0.0);
+        self.winner = match leader(&self.scores) {
+            Some((id, score)) if score >= DEATHMATCH_SCORE_LIMIT || self.time_left <= 0.0 => {
+                Winner::Player(id)
+            }
+            None if self.time_left <= 0.0 => Winner::Draw,
+            _ => Winner::Undecided,
+        };
+    }
+
+    fn winner(&self) -> Winner {
+        self.winner
+    }
+
+    fn status(&self) -> MatchStatus {
+        let mut scores = self
+            .scores
+            .iter()
+            .map(|(id, score)| Score {
+                id: *id,
+                score: *score,
+            })
+            .collect::<Vec<Score>>();
+        scores.sort_by_key(|x| x.id);
+        MatchStatus::new(self.kind(), self.time_left, scores, vec![], self.winner)
+    }
+
+    fn clone_box(&self) -> Box<dyn GameMode> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct TeamDeathmatch {
+    scores: HashMap<u8, i16>,
+    time_left: f32,
+    winner: Winner,
+}
+
+impl TeamDeathmatch {
+    pub fn new() -> Self {
+        Self {
+            scores: (0..TEAM_COUNT).map(|x| (x, 0)).collect(),
+            time_left: MATCH_TIME,
+            winner: Winner::Undecided,
+        }
+    }
+}
+
+impl GameMode for TeamDeathmatch {
+    fn kind(&self) -> ModeKind {
+        ModeKind::TeamDeathmatch
+    }
+
+    fn on_join(&mut self, player: &mut Player, players: &HashMap<u64, Player>) {
+        player.team = Some(balance_team(player.team_choice, players));
+    }
+
+    fn on_leave(&mut self, _player: &Player) {}
+
+    fn on_kill(&mut self, victim: &Player, killer: Option<&Player>) {
+        if self.winner != Winner::Undecided {
+            return;
+        }
+        let Some((killer, team)) = killer.and_then(|x| Some((x, x.team?))) else {
+            return;
+        };
+        if killer.id == victim.id {
+            return;
+        }
+        let points = if victim.team == Some(team) { -1 } else { 1 };
+        *self.scores.entry(team).or_default() += points;
+    }
+
+    fn on_tick(&mut self, _players: &HashMap<u64, Player>, dt: f32) {
+        if self.winner != Winner::Undecided {
+            return;
+        }
+        self.time_left = (self.time_left - dt).max(0.0);
+        self.winner = decide(&self.scores, TEAM_DEATHMATCH_SCORE_LIMIT, self.time_left);
+    }
+
+    fn winner(&self) -> Winner {
+        self.winner
+    }
+
+    fn status(&self) -> MatchStatus {
+        MatchStatus::new(
+            self.kind(),
+            self.time_left,
+            team_scores(&self.scores),
+            vec![],
+            self.winner,
+        )
+    }
+
+    fn clone_box(&self) -> Box<dyn GameMode> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Flag {
+    team: u8,
+    base: Vector3,
+    position: Vector3,
+    state: FlagState,
+    carrier: Option<(u64, EntityId)>,
+    return_timer: f32,
+}
+
+impl Flag {
+    fn new(base: &FlagBase) -> Self {
+        Self {
+            team: base.team,
+            base: base.position,
+            position: base.position,
+            state: FlagState::AtBase,
+            carrier: None,
+            return_timer: 0.0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.position = self.base;
+        self.state = FlagState::AtBase;
+        self.carrier = None;
+    }
+
+    fn drop_at(&mut self, position: Vector3) {
+        self.position = position;
+        self.state = FlagState::Dropped;
+        self.carrier = None;
+        self.return_timer = FLAG_RETURN_TIME;
+    }
+
+    fn status(&self) -> FlagStatus {
+        FlagStatus {
+            team: self.team,
+            state: self.state,
+            carrier: self.carrier.map_or(EntityId::MAX, |x| x.1),
+            position: self.position.to_array(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CaptureTheFlag {
+    scores: HashMap<u8, i16>,
+    flags: Vec<Flag>,
+    time_left: f32,
+    winner: Winner,
+}
+
+impl CaptureTheFlag {
+    pub fn new(flag_bases: &[FlagBase]) -> Self {
+        Self {
+            scores: (0..TEAM_COUNT).map(|x| (x, 0)).collect(),
+            flags: flag_bases.iter().map(Flag::new).collect(),
+            time_left: MATCH_TIME,
+            winner: Winner::Undecided,
+        }
+    }
+
+    fn drop_carried(&mut self, player: &Player) {
+        for flag in self.flags.iter_mut() {
+            if flag.carrier.map(|x| x.0) == Some(player.id) {
+                flag.drop_at(player.position);
+            }
+        }
+    }
+
+    fn update_flag(&mut self, index: usize, players: &HashMap<u64, Player>, dt: f32) {
+        let flag = &mut self.flags[index];
+        if let Some((carrier, _)) = flag.carrier {
+            match players.get(&carrier) {
+                Some(player) if player.alive() => {
+                    let home = self
+                        .flags
+                        .iter()
+                        .find(|x| Some(x.team) == player.team && x.state == FlagState::AtBase);
+                    if home.is_some_and(|x| player.position.distance_to(x.base) <= FLAG_RADIUS) {
+                        let team = player.team.unwrap_or(0);
+                        *self.scores.entry(team).or_default() += 1;
+                        self.flags[index].reset();
+                    }
+                }
+                Some(player) => flag.drop_at(player.position),
+                None => flag.drop_at(flag.position),
+            }
+            return;
+        }
+        if flag.state == FlagState::Dropped {
+            flag.return_timer -= dt;
+            if flag.return_timer <= 0.0 {
+                flag.reset();
+                return;
+            }
+        }
+        let toucher = players
+            .values()
+            .filter(|x| x.alive() && x.team.is_some())
+            .filter(|x| x.position.distance_to(flag.position) <= FLAG_RADIUS)
+            .min_by_key(|x| x.id);
+        let Some(toucher) = toucher else {
+            return;
+        };
+        let carrying = self
+            .flags
+            .iter()
+            .any(|x| x.carrier.map(|x| x.0) == Some(toucher.id));
+        let flag = &mut self.flags[index];
+        if toucher.team == Some(flag.team) {
+            if flag.state == FlagState::Dropped {
+                flag.reset();
+            }
+        } else if !carrying {
+            flag.state = FlagState::Carried;
+            flag.carrier = Some((toucher.id, toucher.entity));
+        }
+    }
+}
+
+impl GameMode for CaptureTheFlag {
+    fn kind(&self) -> ModeKind {
+        ModeKind::CaptureTheFlag
+    }
+
+    fn on_join(&mut self, player: &mut Player, players: &HashMap<u64, Player>) {
+        player.team = Some(balance_team(player.team_choice, players));
+    }
+
+    fn on_leave(&mut self, player: &Player) {
+        self.drop_carried(player);
+    }
+
+    fn on_kill(&mut self, victim: &Player, _killer: Option<&Player>) {
+        self.drop_carried(victim);
+    }
+
+    fn on_tick(&mut self, players: &HashMap<u64, Player>, dt: f32) {
+        if self.winner != Winner::Undecided {
+            return;
+        }
+        self.time_left = (self.time_left - dt).max(0.0);
+        for index in 0..self.flags.len() {
+            self.update_flag(index, players, dt);
+        }
+        self.winner = decide(&self.scores, CAPTURE_LIMIT, self.time_left);
+    }
+
+    fn winner(&self) -> Winner {
+        self.winner
+    }
+
+    fn status(&self) -> MatchStatus {
+        MatchStatus::new(
+            self.kind(),
+            self.time_left,
+            team_scores(&self.scores),
+            self.flags.iter().map(|x| x.status()).collect(),
+            self.winner,
+        )
+    }
+
+    fn clone_box(&self) -> Box<dyn GameMode> {
+        Box::new(self.clone())
+    }
+}
@@ -1,8 +1,20 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use crate::*;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, Semaphore};
 
-#[derive(DekuRead, DekuWrite)]
+pub const MAX_DATAGRAM_LEN: usize = 65507;
+pub const PACKET_HEADER_LEN: usize = 16;
+pub const MAX_PAYLOAD_LEN: usize = MAX_DATAGRAM_LEN - PACKET_HEADER_LEN;
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+pub const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
 #[deku(type = "u8")]
 pub enum Reason {
     #[deku(id = "0x1")]
@@ -16,193 +28,837 @@ pub enum Reason {
     #[deku(id = "0x5")]
     IdDoesntExist,
     #[deku(id = "0x6")]
-    WrongPassword
+    WrongPassword,
+    #[deku(id = "0x7")]
+    IncompatibleVersion,
+    #[deku(id = "0x8")]
+    Unavailable,
+    #[deku(id = "0x9")]
+    SessionFull,
 }
 
 #[derive(DekuRead, DekuWrite)]
 #[deku(type = "u8")]
 pub enum ServerResponse {
     #[deku(id = "0x1")]
-    Ok(ResponseSignal),
+    Ok(JoinInfo),
     #[deku(id = "0x2")]
     InvalidRequest(Reason),
+    #[deku(id = "0x3")]
+    Sessions(SessionList),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(type = "u8")]
+pub enum Delivery {
+    #[deku(id = "0x0")]
+    Unreliable,
+    #[deku(id = "0x1")]
+    UnreliableSequenced,
+    #[deku(id = "0x2")]
+    ReliableOrdered,
+}
+
+impl Delivery {
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(type = "u8")]
+pub enum PacketKind {
+    #[deku(id = "0x0")]
+    Data,
+    #[deku(id = "0x1")]
+    Ack,
+}
+
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+pub struct Packet {
+    pub token: u64,
+    pub kind: PacketKind,
+    pub delivery: Delivery,
+    pub sequence: u32,
+    #[deku(update = "self.payload.len()")]
+    payload_len: u16,
+    #[deku(count = "payload_len")]
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(
+        token: u64,
+        kind: PacketKind,
+        delivery: Delivery,
+        sequence: u32,
+        payload: Vec<u8>,
+    ) -> Self {
+        Self {
+            token,
+            kind,
+            delivery,
+            sequence,
+            payload_len: payload.len() as u16,
+            payload,
+        }
+    }
+}
+
+pub fn sequence_greater(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < u32::MAX / 2
+}
+
+pub const RESEND_AFTER: Duration = Duration::from_millis(100);
+pub const RECEIVE_WINDOW: u32 = 256;
+
+#[derive(Default)]
+struct Channel {
+    next_send: u32,
+    last_received: Option<u32>,
+    next_expected: u32,
+    buffered: BTreeMap<u32, Vec<u8>>,
+    unacked: BTreeMap<u32, (Vec<u8>, Instant)>,
+}
+
+pub struct Connection {
+    pub token: u64,
+    channels: [Channel; 3],
+    outgoing: VecDeque<Packet>,
+}
+
+impl Connection {
+    pub fn new(token: u64) -> Self {
+        Self {
+            token,
+            channels: Default::default(),
+            outgoing: VecDeque::new(),
+        }
+    }
+
+    pub fn send(&mut self, delivery: Delivery, payload: Vec<u8>) {
+        let channel = &mut self.channels[delivery.index()];
+        let sequence = channel.next_send;
+        channel.next_send = channel.next_send.wrapping_add(1);
+        if delivery == Delivery::ReliableOrdered {
+            channel
+                .unacked
+                .insert(sequence, (payload.clone(), Instant::now()));
+        }
+        self.outgoing.push_back(Packet::new(
+            self.token,
+            PacketKind::Data,
+            delivery,
+            sequence,
+            payload,
+        ));
+    }
+
+    pub fn receive(&mut self, packet: Packet) -> Vec<Vec<u8>> {
+        use Delivery::*;
+        let channel = &mut self.channels[packet.delivery.index()];
+        if packet.kind == PacketKind::Ack {
+            channel.unacked.remove(&packet.sequence);
+            return Vec::new();
+        }
+        match packet.delivery {
+            Unreliable => vec![packet.payload],
+            UnreliableSequenced => {
+                if let Some(last) = channel.last_received {
+                    if !sequence_greater(packet.sequence, last) {
+                        return Vec::new();
+                    }
+                }
+                channel.last_received = Some(packet.sequence);
+                vec![packet.payload]
+            }
+            ReliableOrdered => {
+                let offset = packet.sequence.wrapping_sub(channel.next_expected);
+                let stale = sequence_greater(channel.next_expected, packet.sequence);
+                if offset >= RECEIVE_WINDOW && !stale {
+                    return Vec::new();
+                }
+                self.outgoing.push_back(Packet::new(
+                    self.token,
+                    PacketKind::Ack,
+                    ReliableOrdered,
+                    packet.sequence,
+                    Vec::new(),
+                ));
+                if !stale {
+                    channel.buffered.insert(packet.sequence, packet.payload);
+                }
+                let mut delivered = Vec::new();
+                while let Some(payload) = channel.buffered.remove(&channel.next_expected) {
+                    delivered.push(payload);
+                    channel.next_expected = channel.next_expected.wrapping_add(1);
+                }
+                delivered
+            }
+        }
+    }
+
+    pub fn poll(&mut self, now: Instant) -> Vec<Packet> {
+        let reliable = &mut self.channels[Delivery::ReliableOrdered.index()];
+        for (sequence, (payload, sent_at)) in reliable.unacked.iter_mut() {
+            if now.duration_since(*sent_at) < RESEND_AFTER {
+                continue;
+            }
+            *sent_at = now;
+            self.outgoing.push_back(Packet::new(
+                self.token,
+                PacketKind::Data,
+                Delivery::ReliableOrdered,
+                *sequence,
+                payload.clone(),
+            ));
+        }
+        self.outgoing.drain(..).collect()
+    }
+}
+
+type Routes = Arc<Mutex<HashMap<u64, UnboundedSender<(SocketAddr, Packet)>>>>;
+
+#[derive(Clone)]
+pub struct SessionSocket {
+    socket: Arc<UdpSocket>,
+    routes: Routes,
+}
+
+impl SessionSocket {
+    pub async fn bind(address: &str) -> std::io::Result<Self> {
+        let ip = address
+            .parse::<SocketAddr>()
+            .map(|x| x.ip())
+            .unwrap_or([0, 0, 0, 0].into());
+        let socket = UdpSocket::bind((ip, 0)).await?;
+        Ok(Self {
+            socket: Arc::new(socket),
+            routes: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn register(&self, control: TcpStream, version: u16, capabilities: u32) -> PlayerLink {
+        let mut rng = rand::thread_rng();
+        let mut routes = self.routes.lock().unwrap();
+        let mut token = rng.gen::<u64>();
+        while routes.contains_key(&token) {
+            token = rng.gen::<u64>();
+        }
+        let (sender, packets) = unbounded_channel();
+        routes.insert(token, sender);
+        PlayerLink {
+            token,
+            version,
+            capabilities,
+            control,
+            connection: Connection::new(token),
+            socket: self.socket.clone(),
+            routes: self.routes.clone(),
+            packets,
+            peer: None,
+            frames: VecDeque::new(),
+            inputs: VecDeque::new(),
+            last_input: None,
+            resend: tokio::time::interval(RESEND_AFTER),
+        }
+    }
+
+    pub async fn listen(self) {
+        let mut buffer = vec![0; MAX_DATAGRAM_LEN];
+        loop {
+            let Ok((len, addr)) = self.socket.recv_from(&mut buffer).await else {
+                continue;
+            };
+            let Ok((_, packet)) = Packet::from_bytes((&buffer[..len], 0)) else {
+                continue;
+            };
+            if let Some(route) = self.routes.lock().unwrap().get(&packet.token) {
+                let _ = route.send((addr, packet));
+            }
+        }
+    }
+}
+
+pub struct PlayerLink {
+    pub token: u64,
+    pub version: u16,
+    pub capabilities: u32,
+    pub control: TcpStream,
+    pub connection: Connection,
+    socket: Arc<UdpSocket>,
+    routes: Routes,
+    packets: UnboundedReceiver<(SocketAddr, Packet)>,
+    peer: Option<SocketAddr>,
+    frames: VecDeque<Frame>,
+    inputs: VecDeque<PlayerSignal>,
+    last_input: Option<u32>,
+    resend: tokio::time::Interval,
+}
+
+impl PlayerLink {
+    pub fn join_info(&self, bounds: Bounds) -> JoinInfo {
+        JoinInfo {
+            token: self.token,
+            version: self.version,
+            udp_port: self.socket.local_addr().map(|x| x.port()).unwrap_or(0),
+            bounds,
+        }
+    }
+
+    pub async fn recv_frame(&mut self) -> Option<Frame> {
+        let mut control = [0; 64];
+        loop {
+            if let Some(frame) = self.frames.pop_front() {
+                return Some(frame);
+            }
+            tokio::select! {
+                packet = self.packets.recv() => {
+                    let Some((addr, packet)) = packet else {
+                        return None;
+                    };
+                    self.peer = Some(addr);
+                    for payload in self.connection.receive(packet) {
+                        if let Ok(frame) = decode_frame(&payload) {
+                            self.frames.push_back(frame);
+                        }
+                    }
+                }
+                read = self.control.read(&mut control) => {
+                    if matches!(read, Ok(0) | Err(_)) {
+                        return None;
+                    }
+                }
+                _ = self.resend.tick() => {}
+            }
+            let _ = self.flush().await;
+        }
+    }
+
+    pub async fn recv(&mut self) -> Option<PlayerSignal> {
+        loop {
+            if let Some(signal) = self.inputs.pop_front() {
+                return Some(signal);
+            }
+            let frame = self.recv_frame().await?;
+            let inputs = match frame.kind {
+                MessageKind::PlayerSignal => frame
+                    .decode_version::<PlayerSignal>(self.version)
+                    .map(|x| vec![x]),
+                MessageKind::InputBatch => frame
+                    .decode_version::<InputBatch>(self.version)
+                    .map(|x| x.inputs),
+                _ => continue,
+            };
+            for input in inputs.unwrap_or_default() {
+                if !input.finite() {
+                    continue;
+                }
+                if let Some(last) = self.last_input {
+                    if !sequence_greater(input.sequence, last) {
+                        continue;
+                    }
+                }
+                self.last_input = Some(input.sequence);
+                self.inputs.push_back(input);
+            }
+        }
+    }
+
+    pub async fn send<T: Message>(&mut self, message: &T) -> std::io::Result<()> {
+        if let Ok(bytes) = encode_version(message, self.version) {
+            if bytes.len() > MAX_PAYLOAD_LEN {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("{:?} payload of {} bytes", T::KIND, bytes.len()),
+                ));
+            }
+            self.connection.send(T::DELIVERY, bytes);
+        }
+        self.flush().await
+    }
+
+    pub async fn flush(&mut self) -> std::io::Result<()> {
+        let Some(peer) = self.peer else {
+            return Ok(());
+        };
+        for packet in self.connection.poll(Instant::now()) {
+            let Ok(bytes) = packet.to_bytes() else {
+                continue;
+            };
+            if let Err(err) = self.socket.send_to(&bytes, peer).await {
+                println!("failed to send packet to {}: {}", peer, err);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PlayerLink {
+    fn drop(&mut self) {
+        if let Ok(mut routes) = self.routes.lock() {
+            routes.remove(&self.token);
+        }
+    }
+}
+
+pub struct JoinRequest {
+    pub request: JoinSessionRequest,
+    pub stream: TcpStream,
+    pub admin: bool,
+    pub reply: oneshot::Sender<Result<JoinPlayer, Reason>>,
+}
+
+#[derive(Clone)]
+pub struct SessionHandle {
+    pub sender: UnboundedSender<JoinRequest>,
+    pub password: Option<Arc<PasswordHash>>,
+    pub admin: Option<Arc<PasswordHash>>,
+    pub info: Arc<Mutex<SessionInfo>>,
+}
+
+impl SessionHandle {
+    fn locked(&self, password: &[u8]) -> bool {
+        self.password.is_some() || (self.admin.is_some() && !password.is_empty())
+    }
+
+    fn verify(&self, password: &[u8]) -> Option<bool> {
+        let admin = self.admin.as_ref().map_or(false, |x| x.verify(password));
+        let valid = admin
+            || self
+                .password
+                .as_ref()
+                .map_or(self.admin.is_none(), |x| x.verify(password));
+        valid.then_some(admin)
+    }
 }
 
 pub struct GameNetwork {
     pub address: String,
     listener: Option<TcpListener>,
-    active_sessions: HashMap<
-        String,
-        (
-            Sender<(JoinSessionRequest, TcpStream)>,
-            Receiver<(JoinResponse, Option<JoinPlayer>)>,
-        ),
-    >,
+    active_sessions: HashMap<String, SessionHandle>,
+    pending_sessions: HashSet<String>,
+    limiter: Arc<Mutex<JoinLimiter>>,
+    verifications: Arc<Semaphore>,
     manager: GameManager,
+    request_sender: UnboundedSender<(ServerRequest, TcpStream)>,
+    request_receiver: UnboundedReceiver<(ServerRequest, TcpStream)>,
+    opened_sender: UnboundedSender<(String, Option<SessionHandle>)>,
+    opened_receiver: UnboundedReceiver<(String, Option<SessionHandle>)>,
+    closed_sender: UnboundedSender<String>,
+    closed_receiver: UnboundedReceiver<String>,
 }
 
 impl GameNetwork {
-    pub fn new(address: String) -> Self {
+    pub fn new(address: String) -> Result<Self, ServerError> {
         let mut manager = GameManager::new();
-        manager.init_scene("static/models/scene.obj");
-        Self {
+        manager.init_scene(MAP_ROTATION[0])?;
+        manager.loadout = load_loadout(WEAPONS_PATH)?;
+        let (request_sender, request_receiver) = unbounded_channel();
+        let (opened_sender, opened_receiver) = unbounded_channel();
+        let (closed_sender, closed_receiver) = unbounded_channel();
+        Ok(Self {
             address,
             listener: None,
             active_sessions: HashMap::new(),
+            pending_sessions: HashSet::new(),
+            limiter: Arc::new(Mutex::new(JoinLimiter::default())),
+            verifications: Arc::new(Semaphore::new(MAX_VERIFICATIONS)),
             manager,
+            request_sender,
+            request_receiver,
+            opened_sender,
+            opened_receiver,
+            closed_sender,
+            closed_receiver,
+        })
+    }
+    async fn open(&mut self) -> Result<(), ServerError> {
+        let listener = TcpListener::bind(&self.address).await?;
+        println!("ready");
+        self.listener = Some(listener);
+        Ok(())
+    }
+    pub async fn start(&mut self) -> Result<(), ServerError> {
+        self.open().await
+    }
+    pub async fn update(&mut self) -> Result<(), ServerError> {
+        use ServerRequest::*;
+
+        let listener = self.listener.as_ref().ok_or(ServerError::NotListening)?;
+        tokio::select! {
+            incoming = listener.accept() => match incoming {
+                Ok((stream, _addr)) => {
+                    tokio::spawn(handshake(stream, self.request_sender.clone()));
+                }
+                Err(err) => {
+                    println!("failed to accept connection: {}", err);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                }
+            },
+            Some((request, stream)) = self.request_receiver.recv() => match request {
+                NewSession(req) => self.create_session(req, stream),
+                JoinSession(req) => self.join_session(req, stream),
+                ListSessions(req) => self.list_sessions(req, stream),
+            },
+            Some((id, handle)) = self.opened_receiver.recv() => self.register_session(id, handle),
+            Some(id) = self.closed_receiver.recv() => {
+                while let Ok((id, handle)) = self.opened_receiver.try_recv() {
+                    self.register_session(id, handle);
+                }
+                println!("closing session {}", id);
+                self.active_sessions.remove(&id);
+            }
         }
+        Ok(())
     }
-    async fn open(&mut self) {
-        if let Ok(listener) = TcpListener::bind(&self.address).await {
-            println!("ready");
-            self.listener = Some(listener);
+    fn register_session(&mut self, id: String, handle: Option<SessionHandle>) {
+        self.pending_sessions.remove(&id);
+        if let Some(handle) = handle {
+            self.active_sessions.insert(id, handle);
         }
     }
-    pub async fn start(&mut self) {
-        self.open().await;
+    fn create_session(&mut self, request: NewSessionRequest, mut stream: TcpStream) {
+        let id = parse_id(&request.id).and_then(|id| {
+            if self.active_sessions.contains_key(&id) || self.pending_sessions.contains(&id) {
+                return Err(Reason::IdInUse.into());
+            }
+            Ok(id)
+        });
+        let id = match id {
+            Ok(id) => id,
+            Err(err) => {
+                println!("failed to create session: {}", err);
+                tokio::spawn(async move {
+                    let _ = reject(&mut stream, &err).await;
+                });
+                return;
+            }
+        };
+        self.pending_sessions.insert(id.clone());
+        let address = self.address.clone();
+        let manager = self.manager.clone();
+        let closed = self.closed_sender.clone();
+        let opened = self.opened_sender.clone();
+        tokio::spawn(async move {
+            match open_session(&address, &request, manager, closed).await {
+                Ok(session) => start_session(session, request, stream, opened).await,
+                Err(err) => {
+                    println!("failed to create session: {}", err);
+                    let _ = opened.send((id, None));
+                    let _ = reject(&mut stream, &err).await;
+                }
+            }
+        });
     }
-    pub async fn update(&mut self) {
-        self.accept_incoming().await;
+    fn list_sessions(&self, request: ListSessionsRequest, mut stream: TcpStream) {
+        let response = match negotiate(request.version) {
+            Some(_) => {
+                let sessions = self
+                    .active_sessions
+                    .values()
+                    .filter(|x| !x.sender.is_closed())
+                    .map(|x| x.info.lock().unwrap().clone())
+                    .filter(|x| request.matches(x))
+                    .collect();
+                ServerResponse::Sessions(SessionList::new(
+                    sessions,
+                    request.page,
+                    request.page_size,
+                ))
+            }
+            None => ServerResponse::InvalidRequest(Reason::IncompatibleVersion),
+        };
+        tokio::spawn(async move {
+            if let Err(err) = write_message(&mut stream, &response).await {
+                println!("failed to list sessions: {}", err);
+            }
+        });
     }
-    async fn accept_incoming(&mut self) {
-        use ServerRequest::*;
-
-        while let Ok((mut stream, _addr)) = self.listener.as_ref().unwrap().accept().await {
-            let mut buffer = [0; 4096];
-            let _ = stream.read(&mut buffer).await;
-            if let Ok((_, request)) = ServerRequest::from_bytes((&buffer, 0)) {
-                match request {
-                    NewSession(req) => self.create_session(req, stream).await,
-                    JoinSession(req) => self.join_session(req, stream).await,
-                };
-            } else {
-                let _ = stream
-                    .write(
-                        &ServerResponse::InvalidRequest(Reason::InvalidRequestFormat)
-                            .to_bytes()
-                            .unwrap(),
-                    )
-                    .await;
-                let _ = stream.shutdown().await;
-            }
-        }
-    }
-    async fn create_session(&mut self, request: NewSessionRequest, mut stream: TcpStream) {
-        let (sender, receiver) = unbounded();
-        if self
-            .active_sessions
-            .contains_key(&String::from_utf8(request.clone().id).unwrap())
-        {
-            stream
-                .write(
-                    &ServerResponse::InvalidRequest(Reason::IdInUse)
-                        .to_bytes()
-                        .unwrap(),
-                )
-                .await
-                .unwrap();
-            return;
-        }
-        let session = Session::new(request.clone(), receiver, self.manager.clone());
-        if let Ok((mut session, receiver)) = session {
-            println!("here!");
-            let _ = stream
-                .write(
-                    &ServerResponse::Ok(ResponseSignal::new(
-                        Vector3::zero(),
-                        Vector3::zero(),
-                        Vector3::zero(),
-                        Vector3::forward(),
-                        Vector3::right(),
-                    ))
-                    .to_bytes()
-                    .unwrap(),
-                )
-                .await;
+    fn join_session(&self, request: JoinSessionRequest, mut stream: TcpStream) {
+        let session = parse_id(&request.id).and_then(|id| {
+            negotiate(request.version).ok_or(Reason::IncompatibleVersion)?;
             self.active_sessions
-                .insert(session.id.clone(), (sender.clone(), receiver));
-            let mut player = session.game_manager.new_player();
-            let dt = session.game_manager.dt;
-            let (mut sender, mut receiver) = (
-                session.game_manager.sender.clone(),
-                session.game_manager.nreceiver.clone(),
-            );
-            tokio::spawn(async move {
-                let mut pipeline = PhysicsPipeline::new();
-                let mut time = tokio::time::Instant::now();
-                loop {
-                    session.update(&mut pipeline, &mut time).await;
-                }
-            });
-
-            tokio::spawn(async move {
-                player
-                    .update(
-                        &mut sender,
-                        &mut receiver,
-                        dt,
-                        Vector3::up() * -9.81,
-                        &mut stream,
-                    )
-                    .await;
-            });
-        } else {
-            println!("ta no else paekk");
-            stream
-                .write(
-                    &ServerResponse::InvalidRequest(session.err().unwrap())
-                        .to_bytes()
-                        .unwrap(),
-                )
-                .await
-                .unwrap();
-        }
-    }
-    async fn join_session(&mut self, request: JoinSessionRequest, mut stream: TcpStream) {
-        use JoinResponse::*;
-        if let Some((sender, receiver)) = self
-            .active_sessions
-            .get(&String::from_utf8(request.id.clone()).unwrap())
-        {
-            sender.send((request, stream)).unwrap();
-
-            let (response, join_info) = receiver.recv().unwrap();
-            match response {
-                Ok => {
-                    let mut join_info = join_info.unwrap();
-                    tokio::spawn(async move {
-                        join_info
-                            .player
-                            .update(
-                                &mut join_info.sender,
-                                &mut join_info.receiver,
-                                join_info.dt,
-                                Vector3::up() * -9.81,
-                                &mut join_info.stream,
-                            )
-                            .await;
-                    });
+                .get(&id)
+                .filter(|x| !x.sender.is_closed())
+                .cloned()
+                .ok_or(ServerError::Protocol(Reason::IdDoesntExist))
+        });
+        let limiter = self.limiter.clone();
+        let verifications = self.verifications.clone();
+        tokio::spawn(async move {
+            let handle = match session {
+                Ok(handle) => handle,
+                Err(err) => {
+                    println!("failed to join session: {}", err);
+                    let _ = reject(&mut stream, &err).await;
+                    return;
                 }
-                _ => {}
+            };
+            if let Err(err) = join(handle, limiter, verifications, request, stream).await {
+                println!("failed to join session: {}", err);
             }
-        } else {
-            stream
-                .write(
-                    &ServerResponse::InvalidRequest(Reason::IdDoesntExist)
-                        .to_bytes()
-                        .unwrap(),
-                )
-                .await
-                .unwrap();
+        });
+    }
+}
+
+async fn open_session(
+    address: &str,
+    request: &NewSessionRequest,
+    manager: GameManager,
+    closed: UnboundedSender<String>,
+) -> Result<(Session, SessionHandle), ServerError> {
+    negotiate(request.version).ok_or(Reason::IncompatibleVersion)?;
+    if request.player_limit == 0 {
+        return Err(Reason::InvalidRequestFormat.into());
+    }
+    if !request.mode.supported(&manager.flag_bases) {
+        return Err(Reason::Unavailable.into());
+    }
+    if request.public != request.password.is_empty() {
+        return Err(Reason::InvalidPassword.into());
+    }
+    let (password, admin) = (request.password.clone(), request.admin_password.clone());
+    let (password, admin) = tokio::task::spawn_blocking(move || {
+        let hash = |x: Vec<u8>| (!x.is_empty()).then(|| Arc::new(PasswordHash::new(&x)));
+        (hash(password), hash(admin))
+    })
+    .await
+    .map_err(|_| Reason::Unavailable)?;
+    let socket = SessionSocket::bind(address).await?;
+    let (sender, receiver) = unbounded_channel();
+    let session = Session::new(request.clone(), receiver, manager, socket, closed)?;
+    let info = session.info.clone();
+    Ok((
+        session,
+        SessionHandle {
+            sender,
+            password,
+            admin,
+            info,
+        },
+    ))
+}
+
+async fn start_session(
+    (mut session, handle): (Session, SessionHandle),
+    request: NewSessionRequest,
+    stream: TcpStream,
+    opened: UnboundedSender<(String, Option<SessionHandle>)>,
+) {
+    let version = negotiate(request.version).unwrap_or(MIN_PROTOCOL_VERSION);
+    let mut link = session
+        .socket
+        .register(stream, version, request.capabilities);
+    let response = ServerResponse::Ok(link.join_info(session.game_manager.bounds));
+    if let Err(err) = write_message(&mut link.control, &response).await {
+        println!("failed to answer session host: {}", err);
+        let _ = opened.send((session.id.clone(), None));
+        return;
+    }
+    let _ = opened.send((session.id.clone(), Some(handle)));
+    let (player, updates) =
+        session
+            .game_manager
+            .new_player(version, request.capabilities, None, false);
+    session.admins.insert(player.id);
+    let inputs = session.game_manager.sender.clone();
+    let leaves = session.game_manager.leave_sender.clone();
+    tokio::spawn(session.run());
+    tokio::spawn(JoinPlayer::new(player.id, inputs, leaves, updates, link).run());
+}
+
+async fn join(
+    handle: SessionHandle,
+    limiter: Arc<Mutex<JoinLimiter>>,
+    verifications: Arc<Semaphore>,
+    request: JoinSessionRequest,
+    mut stream: TcpStream,
+) -> Result<(), ServerError> {
+    let mut admin = false;
+    if handle.locked(&request.password) {
+        let ip = stream.peer_addr()?.ip();
+        if !limiter.lock().unwrap().reserve(ip) {
+            write_message(&mut stream, &JoinResponse::Err(Reason::WrongPassword)).await?;
+            return Err(Reason::WrongPassword.into());
+        }
+        let Ok(permit) = verifications.try_acquire_owned() else {
+            limiter.lock().unwrap().release(ip);
+            write_message(&mut stream, &JoinResponse::Err(Reason::Unavailable)).await?;
+            return Err(Reason::Unavailable.into());
+        };
+        let (checker, supplied) = (handle.clone(), request.password.clone());
+        let verified = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            checker.verify(&supplied)
+        })
+        .await
+        .unwrap_or(None);
+        let Some(verified) = verified else {
+            limiter.lock().unwrap().failed(ip);
+            write_message(&mut stream, &JoinResponse::Err(Reason::WrongPassword)).await?;
+            return Err(Reason::WrongPassword.into());
+        };
+        limiter.lock().unwrap().succeeded(ip);
+        admin = verified;
+    }
+    let (reply, response) = oneshot::channel();
+    handle
+        .sender
+        .send(JoinRequest {
+            request,
+            stream,
+            admin,
+            reply,
+        })
+        .map_err(|_| ServerError::SessionClosed)?;
+    let player = response.await.map_err(|_| ServerError::SessionClosed)??;
+    player.run().await;
+    Ok(())
+}
+
+async fn reject(stream: &mut TcpStream, err: &ServerError) -> Result<(), ServerError> {
+    write_message(stream, &ServerResponse::InvalidRequest(err.reason())).await?;
+    Ok(())
+}
+
+async fn handshake(mut stream: TcpStream, requests: UnboundedSender<(ServerRequest, TcpStream)>) {
+    let request = tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        read_message::<ServerRequest, _>(&mut stream),
+    )
+    .await;
+    match request {
+        Ok(Ok(request)) => {
+            let _ = requests.send((request, stream));
+        }
+        Ok(Err(_)) => {
+            let _ = write_message(
+                &mut stream,
+                &ServerResponse::InvalidRequest(Reason::InvalidRequestFormat),
+            )
+            .await;
+            let _ = stream.shutdown().await;
+        }
+        Err(_) => {
+            println!("handshake timed out");
+            let _ = stream.shutdown().await;
         }
     }
 }
 
-async fn get_signal(stream: &mut TcpStream) -> Option<PlayerSignal> {
-    let mut buffer = [0; 1024];
-    let _ = stream.read(&mut buffer).await;
-    if let Ok((_bytes, signal)) = PlayerSignal::from_bytes((&buffer, 0)) {
-        return Some(signal);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(from: &mut Connection, to: &mut Connection) -> Vec<Vec<u8>> {
+        from.poll(Instant::now())
+            .into_iter()
+            .flat_map(|x| to.receive(x))
+            .collect()
+    }
+
+    #[test]
+    fn sequence_comparison_wraps() {
+        assert!(sequence_greater(1, 0));
+        assert!(sequence_greater(0, u32::MAX));
+        assert!(!sequence_greater(u32::MAX, 0));
+        assert!(!sequence_greater(5, 5));
+    }
+
+    #[test]
+    fn reliable_delivers_in_order_and_acks() {
+        let (mut a, mut b) = (Connection::new(1), Connection::new(1));
+        for payload in [vec![1], vec![2], vec![3]] {
+            a.send(Delivery::ReliableOrdered, payload);
+        }
+        let mut packets = a.poll(Instant::now());
+        packets.reverse();
+        let delivered = packets
+            .into_iter()
+            .flat_map(|x| b.receive(x))
+            .collect::<Vec<_>>();
+        assert_eq!(delivered, vec![vec![1], vec![2], vec![3]]);
+        assert!(transfer(&mut b, &mut a).is_empty());
+        assert!(a.channels[Delivery::ReliableOrdered.index()]
+            .unacked
+            .is_empty());
+    }
+
+    #[test]
+    fn reliable_resends_unacked() {
+        let mut a = Connection::new(1);
+        a.send(Delivery::ReliableOrdered, vec![1]);
+        let now = Instant::now();
+        assert_eq!(a.poll(now).len(), 1);
+        assert!(a.poll(now).is_empty());
+        assert_eq!(a.poll(now + RESEND_AFTER).len(), 1);
+    }
+
+    #[test]
+    fn reliable_survives_wraparound() {
+        let (mut a, mut b) = (Connection::new(1), Connection::new(1));
+        a.channels[Delivery::ReliableOrdered.index()].next_send = u32::MAX - 1;
+        b.channels[Delivery::ReliableOrdered.index()].next_expected = u32::MAX - 1;
+        for payload in [vec![1], vec![2], vec![3], vec![4]] {
+            a.send(Delivery::ReliableOrdered, payload);
+        }
+        let delivered = transfer(&mut a, &mut b);
+        assert_eq!(delivered, vec![vec![1], vec![2], vec![3], vec![4]]);
+        assert_eq!(
+            b.channels[Delivery::ReliableOrdered.index()].next_expected,
+            2
+        );
+    }
+
+    #[test]
+    fn reliable_drops_outside_window() {
+        let mut b = Connection::new(1);
+        let far = Packet::new(
+            1,
+            PacketKind::Data,
+            Delivery::ReliableOrdered,
+            RECEIVE_WINDOW,
+            vec![1],
+        );
+        assert!(b.receive(far).is_empty());
+        assert!(b.channels[Delivery::ReliableOrdered.index()]
+            .buffered
+            .is_empty());
+        assert!(b.poll(Instant::now()).is_empty());
+        let first = Packet::new(1, PacketKind::Data, Delivery::ReliableOrdered, 0, vec![2]);
+        assert_eq!(b.receive(first.clone()), vec![vec![2]]);
+        assert!(b.receive(first).is_empty());
+        assert_eq!(b.poll(Instant::now()).len(), 2);
+    }
+
+    #[test]
+    fn sequenced_drops_stale() {
+        let mut b = Connection::new(1);
+        let packet = |sequence| {
+            Packet::new(
+                1,
+                PacketKind::Data,
+                Delivery::UnreliableSequenced,
+                sequence,
+                vec![0],
+            )
+        };
+        assert_eq!(b.receive(packet(2)).len(), 1);
+        assert!(b.receive(packet(1)).is_empty());
+        assert!(b.receive(packet(2)).is_empty());
+        assert_eq!(b.receive(packet(3)).len(), 1);
+    }
+
+    #[test]
+    fn largest_payload_fits_a_datagram() {
+        let packet = Packet::new(
+            u64::MAX,
+            PacketKind::Data,
+            Delivery::ReliableOrdered,
+            u32::MAX,
+            vec![0; MAX_PAYLOAD_LEN],
+        );
+        assert_eq!(packet.to_bytes().unwrap().len(), MAX_DATAGRAM_LEN);
     }
-    None
 }
@@ -50,21 +50,72 @@ impl Shape {
     }
 }
 
+pub type EntityId = u16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(type = "u8")]
+pub enum EntityKind {
+    #[deku(id = "0x1")]
+    Object,
+    #[deku(id = "0x2")]
+    Player,
+    #[deku(id = "0x3")]
+    Projectile,
+}
+
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+pub struct EntityEntry {
+    pub id: EntityId,
+    pub kind: EntityKind,
+    #[deku(update = "self.name.len()")]
+    name_len: u8,
+    #[deku(count = "name_len")]
+    pub name: Vec<u8>,
+    #[deku(cond = "*kind == EntityKind::Projectile", default = "0.0")]
+    pub lifetime: f32,
+}
+
+impl EntityEntry {
+    pub fn new(id: EntityId, kind: EntityKind, name: &str) -> Self {
+        let name = name.as_bytes()[..name.len().min(u8::MAX as usize)].to_vec();
+        Self {
+            id,
+            kind,
+            name_len: name.len() as u8,
+            name,
+            lifetime: 0.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+pub struct EntityTable {
+    #[deku(update = "self.entries.len()")]
+    count: u16,
+    #[deku(count = "count")]
+    pub entries: Vec<EntityEntry>,
+}
+
+impl EntityTable {
+    pub fn new(entries: Vec<EntityEntry>) -> Self {
+        Self {
+            count: entries.len() as u16,
+            entries,
+        }
+    }
+}
+
 #[derive(Debug, DekuRead, DekuWrite, Clone)]
 pub struct NetworkObject {
+    pub id: EntityId,
     pub position: [f32; 3],
     pub rotation: [f32; 4],
-    #[deku(update = "self.id.len()")]
-    id_len: usize,
-    #[deku(count = "id_len")]
-    pub id: Vec<u8>,
 }
 
 impl NetworkObject {
-    pub fn new(id: String, position: Vector3, rotation: Vector4) -> Self {
+    pub fn new(id: EntityId, position: Vector3, rotation: Vector4) -> Self {
         Self {
-            id: id.as_bytes().to_vec(),
-            id_len: id.len(),
+            id,
             position: position.to_array(),
             rotation: [rotation.x, rotation.y, rotation.z, rotation.w],
         }
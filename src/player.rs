@@ -4,28 +4,131 @@ use rapier3d::control::{CharacterCollision, KinematicCharacterController};
 use rapier3d::na::{Const, OPoint};
 use raylib::math::Vector2;
 
+pub const INPUT_JUMP: u8 = 0x1;
+pub const INPUT_CROUCH: u8 = 0x2;
+pub const INPUT_SPRINT: u8 = 0x4;
+pub const INPUT_FIRE: u8 = 0x8;
+pub const INPUT_ALT_FIRE: u8 = 0x10;
+pub const INPUT_RELOAD: u8 = 0x20;
+pub const INPUT_SCOREBOARD: u8 = 0x40;
+
+pub const SPRINT_MULTIPLIER: f32 = 1.6;
+pub const CROUCH_MULTIPLIER: f32 = 0.5;
+pub const JUMP_SPEED: f32 = 8.0;
+pub const MAX_HEALTH: f32 = 100.0;
+pub const ARMOUR_ABSORPTION: f32 = 0.66;
+pub const FALL_DAMAGE_SPEED: f32 = 15.0;
+pub const FALL_DAMAGE_SCALE: f32 = 5.0;
+pub const RESPAWN_DELAY: f32 = 3.0;
+pub const HEAD_HEIGHT: f32 = 1.6;
+pub const HEAD_RADIUS: f32 = 0.4;
+pub const HEAD_SCALE: f32 = 0.125;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(type = "u8")]
+pub enum PlayerState {
+    #[deku(id = "0x0")]
+    Alive,
+    #[deku(id = "0x1")]
+    Dead,
+    #[deku(id = "0x2")]
+    Spectating,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(type = "u8")]
+pub enum LeaveReason {
+    #[deku(id = "0x0")]
+    Disconnected,
+    #[deku(id = "0x1")]
+    TimedOut,
+}
+
 #[derive(Clone, Debug, DekuRead, DekuWrite)]
+pub struct PlayerLeft {
+    pub id: EntityId,
+    pub reason: LeaveReason,
+}
+
+#[derive(Clone, Debug, Default, DekuRead, DekuWrite)]
+#[deku(ctx = "version: u16", ctx_default = "PROTOCOL_VERSION")]
 pub struct PlayerSignal {
-    desired_mov: [f32; 3],
+    pub sequence: u32,
+    #[deku(cond = "version >= 2", default = "0")]
+    pub view_tick: u32,
+    pub forward: f32,
+    pub strafe: f32,
+    pub buttons: u8,
     desired_rot: [f32; 2],
     camera_radius: f32,
+    ack: u32,
+}
+
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+#[deku(ctx = "version: u16", ctx_default = "PROTOCOL_VERSION")]
+pub struct InputBatch {
+    #[deku(update = "self.inputs.len()")]
+    count: u8,
+    #[deku(count = "count", ctx = "version")]
+    pub inputs: Vec<PlayerSignal>,
+}
+
+impl PlayerSignal {
+    pub fn pressed(&self, button: u8) -> bool {
+        self.buttons & button != 0
+    }
+
+    pub fn finite(&self) -> bool {
+        [self.forward, self.strafe, self.camera_radius]
+            .iter()
+            .chain(self.desired_rot.iter())
+            .all(|x| x.is_finite())
+    }
 }
 
 #[derive(Clone, Debug, DekuRead, DekuWrite)]
+#[deku(ctx = "version: u16", ctx_default = "PROTOCOL_VERSION")]
 pub struct ResponseSignal {
     #[deku(update = "self.players.len()")]
     pub player_count: usize,
     #[deku(update = "self.objects.len()")]
     pub object_count: usize,
+    pub id: EntityId,
+    pub snapshot: u32,
+    pub baseline: u32,
+    pub last_input: u32,
+    #[deku(cond = "version >= 2", default = "0")]
+    pub tick: u32,
+    #[deku(cond = "version >= 2", default = "0")]
+    pub health: u8,
+    #[deku(cond = "version >= 2", default = "0")]
+    pub armour: u8,
+    #[deku(cond = "version >= 2", default = "PlayerState::Alive")]
+    pub state: PlayerState,
+    #[deku(cond = "version >= 2", default = "TEAM_NONE")]
+    pub team: u8,
+    #[deku(cond = "version >= 2", default = "0", update = "self.hits.len()")]
+    pub hit_count: u8,
     pub translation: [f32; 3],
     pub camera_pos: [f32; 3],
     pub camera_target: [f32; 3],
     pub fwd: [f32; 3],
     pub right: [f32; 3],
-    #[deku(count = "player_count")]
+    #[deku(count = "player_count", ctx = "version")]
     pub players: Vec<ResponseSignal>,
     #[deku(count = "object_count")]
     pub objects: Vec<NetworkObject>,
+    #[deku(
+        cond = "version >= 2",
+        count = "hit_count",
+        ctx = "version",
+        default = "Vec::new()"
+    )]
+    pub hits: Vec<HitEvent>,
+    #[deku(cond = "version >= 2", default = "0", update = "self.removed.len()")]
+    pub removed_count: u8,
+    #[deku(cond = "version >= 2", count = "removed_count", default = "Vec::new()")]
+    pub removed: Vec<EntityId>,
 }
 
 impl ResponseSignal {
@@ -39,6 +142,16 @@ impl ResponseSignal {
         Self {
             player_count: 0,
             object_count: 0,
+            id: 0,
+            snapshot: 0,
+            baseline: 0,
+            last_input: 0,
+            tick: 0,
+            health: 0,
+            armour: 0,
+            state: PlayerState::Alive,
+            team: TEAM_NONE,
+            hit_count: 0,
             translation: translation.to_array(),
             camera_pos: camera_pos.to_array(),
             camera_target: camera_target.to_array(),
@@ -46,6 +159,9 @@ impl ResponseSignal {
             right: right.to_array(),
             players: Vec::new(),
             objects: Vec::new(),
+            hits: Vec::new(),
+            removed_count: 0,
+            removed: Vec::new(),
         }
     }
 }
@@ -65,8 +181,12 @@ impl Default for ResponseSignal {
 #[derive(Clone, Debug)]
 pub struct Player {
     pub id: u64,
+    pub entity: EntityId,
     pub obj: KinematicCharacterController,
     pub collider: ColliderHandle,
+    pub head: ColliderHandle,
+    pub head_offset: f32,
+    pub head_radius: f32,
     pub position: Vector3,
     pub fwd: Vector3,
     pub camera_controller: KinematicCharacterController,
@@ -77,6 +197,25 @@ pub struct Player {
     pub mass: f32,
     pub dt: f32,
     pub vertices: Option<(Vec<OPoint<f32, Const<3>>>, Vec<[u32; 3]>)>,
+    pub ack: u32,
+    pub last_input: u32,
+    pub known_entities: u32,
+    pub version: u16,
+    pub capabilities: u32,
+    pub velocity: Vector3,
+    pub acceleration: f32,
+    pub grounded: bool,
+    pub weapons: Vec<WeaponState>,
+    pub health: f32,
+    pub armour: f32,
+    pub state: PlayerState,
+    pub respawn_timer: f32,
+    pub last_attacker: Option<u64>,
+    pub attackers: Vec<u64>,
+    pub team: Option<u8>,
+    pub team_choice: Option<u8>,
+    pub scoreboard_requested: bool,
+    buttons: u8,
     camera_radius: f32,
     pitch: f32,
     yaw: f32,
@@ -94,11 +233,15 @@ impl Player {
     ) -> Self {
         Self {
             id,
+            entity: 0,
             pitch: 0.0,
             yaw: 0.0,
             speed,
             obj: handle,
             collider,
+            head: ColliderHandle::invalid(),
+            head_offset: HEAD_HEIGHT,
+            head_radius: HEAD_RADIUS,
             position,
             fwd: Vector3::forward(),
             right: Vector3::right(),
@@ -108,37 +251,187 @@ impl Player {
             camera_target: Vector3::forward(),
             dt: 0.0,
             vertices: None,
+            ack: 0,
+            last_input: 0,
+            known_entities: 0,
+            version: PROTOCOL_VERSION,
+            capabilities: 0,
+            velocity: Vector3::zero(),
+            acceleration: speed * 10.0,
+            grounded: false,
+            weapons: Vec::new(),
+            health: MAX_HEALTH,
+            armour: 0.0,
+            state: PlayerState::Alive,
+            respawn_timer: 0.0,
+            last_attacker: None,
+            attackers: Vec::new(),
+            team: None,
+            team_choice: None,
+            scoreboard_requested: false,
+            buttons: 0,
             camera_radius: 5.0,
         }
     }
 
-    pub async fn update(
-        &mut self,
-        sender: &mut Sender<Player>,
-        receiver: &mut Receiver<(Player, ResponseSignal)>,
-        dt: f32,
-        gravity: Vector3,
-        stream: &mut TcpStream,
-    ) {
-        while let Some(state) = self.get_state(stream).await {
-            self.position += Vector3::new(
-                state.desired_mov[0],
-                state.desired_mov[1],
-                state.desired_mov[2],
-            ) + gravity * dt;
+    pub fn simulate(&mut self, input: Option<&PlayerSignal>, dt: f32, gravity: Vector3) {
+        let idle = PlayerSignal::default();
+        let state = input.unwrap_or(&idle);
+        if self.alive() {
+            self.apply_input(state, dt, gravity);
+        }
+        for weapon in self.weapons.iter_mut() {
+            weapon.update(dt);
+        }
+        if let Some(state) = input {
+            if state.pressed(INPUT_RELOAD) {
+                self.weapons.iter_mut().for_each(|x| x.start_reload());
+            }
+            if state.pressed(INPUT_SCOREBOARD) && self.buttons & INPUT_SCOREBOARD == 0 {
+                self.scoreboard_requested = true;
+            }
+            self.buttons = state.buttons;
+            self.last_input = state.sequence;
             self.camera_radius = state.camera_radius.clamp(2.5, 20.0);
+            self.ack = state.ack;
+        }
+
+        self.camera_position = Vector3::new(self.position.x, self.position.y, self.position.z)
+            + Vector3::up() * 5.0
+            - self.fwd * self.camera_radius;
 
-            self.camera_position = Vector3::new(self.position.x, self.position.y, self.position.z)
-                + Vector3::up() * 5.0
-                - self.fwd * self.camera_radius;
+        self.update_camera(dt, Vector2::new(state.desired_rot[0], state.desired_rot[1]));
+    }
 
-            self.update_camera(dt, Vector2::new(state.desired_rot[0], state.desired_rot[1]));
-            sender.send(self.clone()).unwrap();
-            let (player, signal) = receiver.recv().unwrap();
-            *self = player;
-            println!("{:?}", signal);
-            let _ = stream.write_all(&signal.to_bytes().unwrap()).await;
+    pub fn equip(&mut self, loadout: &[WeaponDef]) {
+        self.weapons = loadout.iter().cloned().map(WeaponState::new).collect();
+    }
+
+    pub fn try_fire(
+        &mut self,
+        input: Option<&PlayerSignal>,
+        slot: usize,
+        button: u8,
+    ) -> Option<WeaponDef> {
+        let input = input?;
+        if !self.alive() || !input.pressed(button) {
+            return None;
         }
+        let weapon = self.weapons.get_mut(slot)?;
+        weapon.fire().then(|| weapon.def.clone())
+    }
+
+    pub fn alive(&self) -> bool {
+        self.state == PlayerState::Alive
+    }
+
+    pub fn spectating(&self) -> bool {
+        self.state == PlayerState::Spectating
+    }
+
+    pub fn damage(&mut self, amount: f32, attacker: Option<u64>) -> f32 {
+        if !self.alive() {
+            return 0.0;
+        }
+        let before = self.health + self.armour;
+        let absorbed = (amount * ARMOUR_ABSORPTION).min(self.armour);
+        self.armour -= absorbed;
+        self.health = (self.health - (amount - absorbed)).max(0.0);
+        if let Some(attacker) = attacker {
+            self.last_attacker = Some(attacker);
+            if attacker != self.id && !self.attackers.contains(&attacker) {
+                self.attackers.push(attacker);
+            }
+        }
+        before - self.health - self.armour
+    }
+
+    pub fn land(&mut self, vertical_speed: f32) {
+        let excess = -vertical_speed - FALL_DAMAGE_SPEED;
+        if excess > 0.0 {
+            self.damage(excess * FALL_DAMAGE_SCALE, None);
+        }
+    }
+
+    pub fn die(&mut self) {
+        self.state = PlayerState::Dead;
+        self.respawn_timer = RESPAWN_DELAY;
+        self.velocity = Vector3::zero();
+    }
+
+    pub fn respawn(&mut self, position: Vector3) {
+        self.state = PlayerState::Alive;
+        self.health = MAX_HEALTH;
+        self.armour = 0.0;
+        self.position = position;
+        self.velocity = Vector3::zero();
+        self.grounded = false;
+        self.last_attacker = None;
+        self.attackers.clear();
+        for weapon in self.weapons.iter_mut() {
+            *weapon = WeaponState::new(weapon.def.clone());
+        }
+    }
+
+    pub fn teammate(&self, team: Option<u8>) -> bool {
+        self.team.is_some() && self.team == team
+    }
+
+    pub fn head_position(&self) -> Vector3 {
+        self.position + Vector3::up() * self.head_offset
+    }
+
+    pub fn fit_head(&mut self) {
+        let Some((vertices, _)) = self.vertices.as_ref() else {
+            return;
+        };
+        let (bottom, top) = vertices
+            .iter()
+            .fold((f32::MAX, f32::MIN), |(low, high), x| {
+                (low.min(x[1]), high.max(x[1]))
+            });
+        if bottom > top {
+            return;
+        }
+        self.head_radius = ((top - bottom) * HEAD_SCALE).max(HEAD_RADIUS);
+        self.head_offset = top - self.head_radius;
+    }
+
+    fn wish_direction(&self, state: &PlayerSignal) -> Vector3 {
+        let fwd = Vector3::new(self.fwd.x, 0.0, self.fwd.z).normalized();
+        let right = Vector3::new(self.right.x, 0.0, self.right.z).normalized();
+        let wish = fwd * state.forward.clamp(-1.0, 1.0) + right * state.strafe.clamp(-1.0, 1.0);
+        if wish.length() > 1.0 {
+            wish.normalized()
+        } else {
+            wish
+        }
+    }
+
+    fn apply_input(&mut self, state: &PlayerSignal, dt: f32, gravity: Vector3) {
+        let mut speed = self.speed;
+        if state.pressed(INPUT_SPRINT) {
+            speed *= SPRINT_MULTIPLIER;
+        }
+        if state.pressed(INPUT_CROUCH) {
+            speed *= CROUCH_MULTIPLIER;
+        }
+        let target = self.wish_direction(state) * speed;
+        let change = target - Vector3::new(self.velocity.x, 0.0, self.velocity.z);
+        let max_change = self.acceleration * dt;
+        let change = if change.length() > max_change {
+            change.normalized() * max_change
+        } else {
+            change
+        };
+        self.velocity.x += change.x;
+        self.velocity.z += change.z;
+        if state.pressed(INPUT_JUMP) && self.grounded {
+            self.velocity.y = JUMP_SPEED;
+            self.grounded = false;
+        }
+        self.velocity += gravity * dt;
+        self.position += self.velocity * dt;
     }
 
     pub fn update_camera(&mut self, dt: f32, delta: Vector2) {
@@ -151,14 +444,27 @@ impl Player {
         self.right = Vector3::new(-target.z, 0.0, target.x);
         self.camera_target = self.camera_position + target * dt;
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    async fn get_state(&mut self, stream: &mut TcpStream) -> Option<PlayerSignal> {
-        let mut buffer = [0; 1024];
-        stream.read(&mut buffer).await.unwrap();
-        let result = PlayerSignal::from_bytes((&buffer, 0));
-        if let Ok(signal) = result {
-            return Some(signal.1);
+    #[test]
+    fn rejects_non_finite_inputs() {
+        let mut input = PlayerSignal {
+            forward: 1.0,
+            camera_radius: 5.0,
+            ..Default::default()
+        };
+        assert!(input.finite());
+        for value in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            input.strafe = value;
+            assert!(!input.finite());
+            input.strafe = 0.0;
+            input.desired_rot[1] = value;
+            assert!(!input.finite());
+            input.desired_rot[1] = 0.0;
         }
-        None
     }
 }
@@ -0,0 +1,41 @@
+use crate::*;
+
+pub const PROJECTILE_SPEED: f32 = 40.0;
+pub const PROJECTILE_RADIUS: f32 = 0.25;
+pub const PROJECTILE_LIFETIME: f32 = 5.0;
+pub const PROJECTILE_SPAWN_OFFSET: f32 = 3.0;
+pub const EXPLOSION_RADIUS: f32 = 6.0;
+pub const EXPLOSION_IMPULSE: f32 = 500.0;
+
+#[derive(Clone, Debug)]
+pub struct Projectile {
+    pub object: NetworkObject,
+    pub owner: u64,
+    pub collider: ColliderHandle,
+    pub body: RigidBodyHandle,
+    pub lifetime: f32,
+    pub damage: f32,
+}
+
+impl Projectile {
+    pub fn new(
+        object: NetworkObject,
+        owner: u64,
+        collider: ColliderHandle,
+        body: RigidBodyHandle,
+        damage: f32,
+    ) -> Self {
+        Self {
+            object,
+            owner,
+            collider,
+            body,
+            lifetime: PROJECTILE_LIFETIME,
+            damage,
+        }
+    }
+}
+
+pub fn falloff(distance: f32) -> f32 {
+    (1.0 - distance / EXPLOSION_RADIUS).clamp(0.0, 1.0)
+}
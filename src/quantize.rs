@@ -0,0 +1,420 @@
+use crate::*;
+
+const POSITION_STEPS: f32 = u16::MAX as f32;
+const SMALLEST_THREE_BITS: u32 = 10;
+const SMALLEST_THREE_STEPS: f32 = ((1 << SMALLEST_THREE_BITS) - 1) as f32;
+const SMALLEST_THREE_RANGE: f32 = std::f32::consts::FRAC_1_SQRT_2;
+const OCTAHEDRAL_STEPS: f32 = u16::MAX as f32;
+
+pub const SMALLEST_THREE_MAX_ERROR: f32 = SMALLEST_THREE_RANGE / SMALLEST_THREE_STEPS * 3.0;
+pub const OCTAHEDRAL_MAX_ERROR: f32 = 4.0 / OCTAHEDRAL_STEPS;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(type = "u8")]
+pub enum SnapshotEncoding {
+    #[deku(id = "0x0")]
+    Full,
+    #[deku(id = "0x1")]
+    Quantized,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, DekuRead, DekuWrite)]
+pub struct Bounds {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+}
+
+impl Bounds {
+    pub fn new(min: [f32; 3], max: [f32; 3]) -> Self {
+        Self { min, max }
+    }
+
+    pub fn padded(&self, margin: f32) -> Self {
+        Self {
+            min: self.min.map(|x| x - margin),
+            max: self.max.map(|x| x + margin),
+        }
+    }
+
+    pub fn include(&mut self, point: [f32; 3]) {
+        for (axis, value) in point.iter().enumerate() {
+            self.min[axis] = self.min[axis].min(*value);
+            self.max[axis] = self.max[axis].max(*value);
+        }
+    }
+
+    pub fn max_error(&self) -> [f32; 3] {
+        std::array::from_fn(|axis| (self.max[axis] - self.min[axis]) / POSITION_STEPS / 2.0)
+    }
+}
+
+impl Default for Bounds {
+    fn default() -> Self {
+        Self::new([-1024.0; 3], [1024.0; 3])
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
+pub struct QuantizedPosition {
+    pub x: u16,
+    pub y: u16,
+    pub z: u16,
+}
+
+impl QuantizedPosition {
+    pub fn encode(position: [f32; 3], bounds: &Bounds) -> Self {
+        let quantized: [u16; 3] = std::array::from_fn(|axis| {
+            let range = bounds.max[axis] - bounds.min[axis];
+            let normalized = ((position[axis] - bounds.min[axis]) / range).clamp(0.0, 1.0);
+            (normalized * POSITION_STEPS).round() as u16
+        });
+        Self {
+            x: quantized[0],
+            y: quantized[1],
+            z: quantized[2],
+        }
+    }
+
+    pub fn decode(&self, bounds: &Bounds) -> [f32; 3] {
+        let quantized = [self.x, self.y, self.z];
+        std::array::from_fn(|axis| {
+            let range = bounds.max[axis] - bounds.min[axis];
+            bounds.min[axis] + quantized[axis] as f32 / POSITION_STEPS * range
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
+pub struct SmallestThree {
+    pub packed: u32,
+}
+
+impl SmallestThree {
+    pub fn encode(rotation: [f32; 4]) -> Self {
+        let length = rotation.iter().map(|x| x * x).sum::<f32>().sqrt();
+        let mut rotation = if length > 0.0 {
+            rotation.map(|x| x / length)
+        } else {
+            [0.0, 0.0, 0.0, 1.0]
+        };
+        let largest = (0..4)
+            .max_by(|a, b| rotation[*a].abs().total_cmp(&rotation[*b].abs()))
+            .unwrap();
+        if rotation[largest] < 0.0 {
+            rotation = rotation.map(|x| -x);
+        }
+        let mut packed = largest as u32;
+        for (index, value) in rotation.iter().enumerate() {
+            if index == largest {
+                continue;
+            }
+            let normalized = (value / SMALLEST_THREE_RANGE + 1.0) / 2.0;
+            let quantized = (normalized.clamp(0.0, 1.0) * SMALLEST_THREE_STEPS).round() as u32;
+            packed = (packed << SMALLEST_THREE_BITS) | quantized;
+        }
+        Self { packed }
+    }
+
+    pub fn decode(&self) -> [f32; 4] {
+        let mask = (1 << SMALLEST_THREE_BITS) - 1;
+        let largest = (self.packed >> (SMALLEST_THREE_BITS * 3)) as usize & 0x3;
+        let mut rotation = [0.0; 4];
+        let mut shift = SMALLEST_THREE_BITS * 3;
+        for (index, value) in rotation.iter_mut().enumerate() {
+            if index == largest {
+                continue;
+            }
+            shift -= SMALLEST_THREE_BITS;
+            let quantized = (self.packed >> shift) & mask;
+            *value = (quantized as f32 / SMALLEST_THREE_STEPS * 2.0 - 1.0) * SMALLEST_THREE_RANGE;
+        }
+        let sum = rotation.iter().map(|x| x * x).sum::<f32>();
+        rotation[largest] = (1.0 - sum).max(0.0).sqrt();
+        rotation
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
+pub struct Octahedral {
+    pub u: u16,
+    pub v: u16,
+}
+
+fn sign(value: f32) -> f32 {
+    if value >= 0.0 {
+        1.0
+    } else {
+        -1.0
+    }
+}
+
+impl Octahedral {
+    pub fn encode(direction: [f32; 3]) -> Self {
+        let [x, y, z] = direction;
+        let l1 = x.abs() + y.abs() + z.abs();
+        let (mut u, mut v) = if l1 > 0.0 {
+            (x / l1, y / l1)
+        } else {
+            (0.0, 0.0)
+        };
+        if z < 0.0 {
+            (u, v) = ((1.0 - v.abs()) * sign(u), (1.0 - u.abs()) * sign(v));
+        }
+        let quantize =
+            |value: f32| ((value.clamp(-1.0, 1.0) + 1.0) / 2.0 * OCTAHEDRAL_STEPS).round() as u16;
+        Self {
+            u: quantize(u),
+            v: quantize(v),
+        }
+    }
+
+    pub fn decode(&self) -> [f32; 3] {
+        let u = self.u as f32 / OCTAHEDRAL_STEPS * 2.0 - 1.0;
+        let v = self.v as f32 / OCTAHEDRAL_STEPS * 2.0 - 1.0;
+        let z = 1.0 - u.abs() - v.abs();
+        let (x, y) = if z < 0.0 {
+            ((1.0 - v.abs()) * sign(u), (1.0 - u.abs()) * sign(v))
+        } else {
+            (u, v)
+        };
+        let length = (x * x + y * y + z * z).sqrt();
+        [x / length, y / length, z / length]
+    }
+}
+
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+pub struct QuantizedObject {
+    pub id: EntityId,
+    pub position: QuantizedPosition,
+    pub rotation: SmallestThree,
+}
+
+impl QuantizedObject {
+    pub fn new(object: &NetworkObject, bounds: &Bounds) -> Self {
+        Self {
+            id: object.id,
+            position: QuantizedPosition::encode(object.position, bounds),
+            rotation: SmallestThree::encode(object.rotation),
+        }
+    }
+}
+
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+#[deku(ctx = "version: u16", ctx_default = "PROTOCOL_VERSION")]
+pub struct QuantizedSignal {
+    #[deku(update = "self.players.len()")]
+    pub player_count: usize,
+    #[deku(update = "self.objects.len()")]
+    pub object_count: usize,
+    pub id: EntityId,
+    pub snapshot: u32,
+    pub baseline: u32,
+    pub last_input: u32,
+    #[deku(cond = "version >= 2", default = "0")]
+    pub tick: u32,
+    #[deku(cond = "version >= 2", default = "0")]
+    pub health: u8,
+    #[deku(cond = "version >= 2", default = "0")]
+    pub armour: u8,
+    #[deku(cond = "version >= 2", default = "PlayerState::Alive")]
+    pub state: PlayerState,
+    #[deku(cond = "version >= 2", default = "TEAM_NONE")]
+    pub team: u8,
+    #[deku(cond = "version >= 2", default = "0", update = "self.hits.len()")]
+    pub hit_count: u8,
+    pub translation: QuantizedPosition,
+    pub camera_pos: QuantizedPosition,
+    pub camera_target: QuantizedPosition,
+    pub fwd: Octahedral,
+    pub right: Octahedral,
+    #[deku(count = "player_count", ctx = "version")]
+    pub players: Vec<QuantizedSignal>,
+    #[deku(count = "object_count")]
+    pub objects: Vec<QuantizedObject>,
+    #[deku(
+        cond = "version >= 2",
+        count = "hit_count",
+        ctx = "version",
+        default = "Vec::new()"
+    )]
+    pub hits: Vec<HitEvent>,
+    #[deku(cond = "version >= 2", default = "0", update = "self.removed.len()")]
+    pub removed_count: u8,
+    #[deku(cond = "version >= 2", count = "removed_count", default = "Vec::new()")]
+    pub removed: Vec<EntityId>,
+}
+
+impl QuantizedSignal {
+    pub fn new(signal: &ResponseSignal, bounds: &Bounds) -> Self {
+        Self {
+            player_count: signal.players.len(),
+            object_count: signal.objects.len(),
+            id: signal.id,
+            snapshot: signal.snapshot,
+            baseline: signal.baseline,
+            last_input: signal.last_input,
+            tick: signal.tick,
+            health: signal.health,
+            armour: signal.armour,
+            state: signal.state,
+            team: signal.team,
+            hit_count: signal.hits.len() as u8,
+            translation: QuantizedPosition::encode(signal.translation, bounds),
+            camera_pos: QuantizedPosition::encode(signal.camera_pos, bounds),
+            camera_target: QuantizedPosition::encode(signal.camera_target, bounds),
+            fwd: Octahedral::encode(signal.fwd),
+            right: Octahedral::encode(signal.right),
+            players: signal
+                .players
+                .iter()
+                .map(|x| QuantizedSignal::new(x, bounds))
+                .collect(),
+            objects: signal
+                .objects
+                .iter()
+                .map(|x| QuantizedObject::new(x, bounds))
+                .collect(),
+            hits: signal.hits.clone(),
+            removed_count: signal.removed.len() as u8,
+            removed: signal.removed.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+
+    const SAMPLES: usize = 10_000;
+
+    fn unit<const N: usize>(rng: &mut StdRng) -> [f32; N] {
+        loop {
+            let value: [f32; N] = std::array::from_fn(|_| rng.gen_range(-1.0..1.0));
+            let length = value.iter().map(|x| x * x).sum::<f32>().sqrt();
+            if length > 0.01 {
+                return value.map(|x| x / length);
+            }
+        }
+    }
+
+    #[test]
+    fn position_round_trip_within_bounds_error() {
+        let mut rng = StdRng::seed_from_u64(6);
+        let bounds = Bounds::new([-300.0, -20.0, -150.0], [500.0, 80.0, 150.0]).padded(4.0);
+        let max_error = bounds.max_error();
+        for _ in 0..SAMPLES {
+            let position: [f32; 3] =
+                std::array::from_fn(|axis| rng.gen_range(bounds.min[axis]..bounds.max[axis]));
+            let decoded = QuantizedPosition::encode(position, &bounds).decode(&bounds);
+            for axis in 0..3 {
+                let error = (decoded[axis] - position[axis]).abs();
+                assert!(
+                    error <= max_error[axis] * 1.01,
+                    "{:?} -> {:?}",
+                    position,
+                    decoded
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn position_outside_bounds_is_clamped() {
+        let bounds = Bounds::new([0.0; 3], [10.0; 3]);
+        let decoded = QuantizedPosition::encode([-5.0, 5.0, 50.0], &bounds).decode(&bounds);
+        assert_eq!(decoded[0], 0.0);
+        assert_eq!(decoded[2], 10.0);
+    }
+
+    #[test]
+    fn smallest_three_round_trip_within_error() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut samples = vec![
+            [0.0, 0.0, 0.0, 1.0],
+            [0.5, 0.5, 0.5, 0.5],
+            [0.5, -0.5, 0.5, -0.5],
+        ];
+        samples.extend((0..SAMPLES).map(|_| unit::<4>(&mut rng)));
+        for rotation in samples {
+            let decoded = SmallestThree::encode(rotation).decode();
+            let flip = if rotation
+                .iter()
+                .zip(decoded)
+                .map(|(a, b)| a * b)
+                .sum::<f32>()
+                < 0.0
+            {
+                -1.0
+            } else {
+                1.0
+            };
+            for (a, b) in rotation.iter().zip(decoded) {
+                assert!(
+                    (a - b * flip).abs() <= SMALLEST_THREE_MAX_ERROR,
+                    "{:?} -> {:?}",
+                    rotation,
+                    decoded
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn octahedral_round_trip_within_error() {
+        let mut rng = StdRng::seed_from_u64(8);
+        let mut samples = vec![
+            [0.0, 0.0, 1.0],
+            [0.0, 0.0, -1.0],
+            [1.0, 0.0, 0.0],
+            [0.0, -1.0, 0.0],
+        ];
+        samples.extend((0..SAMPLES).map(|_| unit::<3>(&mut rng)));
+        for direction in samples {
+            let decoded = Octahedral::encode(direction).decode();
+            for (a, b) in direction.iter().zip(decoded) {
+                assert!(
+                    (a - b).abs() <= OCTAHEDRAL_MAX_ERROR,
+                    "{:?} -> {:?}",
+                    direction,
+                    decoded
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bounds_round_trip() {
+        let bounds = Bounds::new([-1.5, 0.0, 2.0], [3.0, 4.5, 6.0]);
+        let bytes = bounds.to_bytes().unwrap();
+        assert_eq!(Bounds::from_bytes((bytes.as_slice(), 0)).unwrap().1, bounds);
+    }
+
+    #[test]
+    fn quantized_signal_keeps_team() {
+        let bounds = Bounds::default();
+        let mut signal = ResponseSignal::default();
+        signal.team = 1;
+        let mut other = ResponseSignal::default();
+        other.team = 2;
+        signal.players.push(other);
+        let mut quantized = QuantizedSignal::new(&signal, &bounds);
+        quantized.update().unwrap();
+
+        let bytes = encode_version(&quantized, PROTOCOL_VERSION).unwrap();
+        let newest = decode_frame(&bytes)
+            .unwrap()
+            .decode_version::<QuantizedSignal>(PROTOCOL_VERSION)
+            .unwrap();
+        assert_eq!(newest.team, 1);
+        assert_eq!(newest.players[0].team, 2);
+
+        let bytes = encode_version(&quantized, MIN_PROTOCOL_VERSION).unwrap();
+        let older = decode_frame(&bytes)
+            .unwrap()
+            .decode_version::<QuantizedSignal>(MIN_PROTOCOL_VERSION)
+            .unwrap();
+        assert_eq!(older.team, TEAM_NONE);
+        assert_eq!(older.players[0].team, TEAM_NONE);
+    }
+}
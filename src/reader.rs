@@ -5,6 +5,50 @@ use rapier3d::{
     na::{Const, OPoint},
 };
 
+pub const SPAWN_MARKER: &str = "Spawn";
+
+#[derive(Clone, Debug)]
+pub struct SpawnPoint {
+    pub position: Vector3,
+    pub team: Option<u8>,
+}
+
+pub fn spawn_point(object: &Object) -> Option<SpawnPoint> {
+    let tag = object.name.get(2..)?.strip_prefix(SPAWN_MARKER)?;
+    let team = match tag.strip_prefix('_') {
+        Some(team) => Some(team.parse::<u8>().ok()?),
+        None if tag.is_empty() => None,
+        None => return None,
+    };
+    Some(SpawnPoint {
+        position: object.position,
+        team,
+    })
+}
+
+pub const FLAG_MARKER: &str = "Flag_";
+
+#[derive(Clone, Debug)]
+pub struct FlagBase {
+    pub position: Vector3,
+    pub team: u8,
+}
+
+pub fn flag_base(object: &Object) -> Option<FlagBase> {
+    let team = object.name.get(2..)?.strip_prefix(FLAG_MARKER)?;
+    Some(FlagBase {
+        position: object.position,
+        team: team.parse::<u8>().ok()?,
+    })
+}
+
+pub const TEAM_MARKER: &str = "_Team";
+
+pub fn team_only(name: &str) -> Option<u8> {
+    let (_, team) = name.get(2..)?.rsplit_once(TEAM_MARKER)?;
+    team.parse::<u8>().ok()
+}
+
 fn move_to_origin(vertices: &mut Vec<OPoint<f32, Const<3>>>) -> (Vector3, f32) {
     let mins = get_max_axis(vertices);
     let (min_x, max_x) = (mins[0].0, mins[0].1);
@@ -38,9 +82,8 @@ fn get_max_axis(vertices: &mut Vec<OPoint<f32, Const<3>>>) -> Vec<(f32, f32)> {
     ]
 }
 
-pub fn load_scene(file_path: &str) -> Vec<Object> {
-    let bytes = std::fs::read(file_path).unwrap();
-    let file_str = String::from_utf8(bytes).unwrap();
+pub fn load_scene(file_path: &str) -> Result<Vec<Object>, ServerError> {
+    let file_str = std::fs::read_to_string(file_path)?;
 
     let mut vertices = Vec::<OPoint<f32, Const<3>>>::new();
     let mut indices = Vec::<[u32; 3]>::new();
@@ -48,101 +91,107 @@ pub fn load_scene(file_path: &str) -> Vec<Object> {
     let mut vertice_count = 0;
     let mut last_vertice_count = 0;
 
-    let mut obj_info = "";
-    let mut obj_rotation = Vector3::zero();
-    let mut body_type: Option<RigidBodyType> = None;
-    let mut object_shape: Option<S> = None;
+    let mut header: Option<(&str, Vector3, RigidBodyType, S)> = None;
 
     let mut objects = Vec::<Object>::new();
 
-    for line in file_str.lines().into_iter() {
+    for (number, line) in file_str.lines().enumerate() {
+        let invalid = || ServerError::Scene(format!("{}:{}: {}", file_path, number + 1, line));
         let tokens: Vec<&str> = line.split_whitespace().collect();
-        match tokens[0] {
-            "v" => {
+        match tokens.first().copied() {
+            Some("v") => {
                 let coordinates = [
-                    tokens[1].parse::<f32>().unwrap(),
-                    tokens[2].parse::<f32>().unwrap(),
-                    tokens[3].parse::<f32>().unwrap(),
+                    token::<f32>(&tokens, 1).ok_or_else(invalid)?,
+                    token::<f32>(&tokens, 2).ok_or_else(invalid)?,
+                    token::<f32>(&tokens, 3).ok_or_else(invalid)?,
                 ];
                 vertices.push(OPoint::from_slice(&coordinates));
                 vertice_count += 1;
             }
-            "f" => {
-                let indexes = [
-                    split(tokens[1]) - last_vertice_count,
-                    split(tokens[2]) - last_vertice_count,
-                    split(tokens[3]) - last_vertice_count,
-                ];
+            Some("f") => {
+                let mut indexes = [0; 3];
+                for (corner, index) in indexes.iter_mut().enumerate() {
+                    *index = tokens
+                        .get(corner + 1)
+                        .and_then(|x| split(x))
+                        .and_then(|x| x.checked_sub(last_vertice_count))
+                        .filter(|x| *x < vertice_count - last_vertice_count)
+                        .ok_or_else(invalid)?;
+                }
                 indices.push(indexes);
             }
-            "o" => {
-                if object_shape.is_some() {
-                    let (obj_position, radius) = move_to_origin(&mut vertices);
-
+            Some("o") => {
+                if let Some(header) = header {
+                    objects.push(build_object(header, &mut vertices, &indices)?);
                     last_vertice_count = vertice_count;
-                    objects.push(Object::new(
-                        object_shape.clone().unwrap(),
-                        body_type.unwrap(),
-                        vertices.clone(),
-                        indices.clone(),
-                        obj_rotation,
-                        obj_position,
-                        radius,
-                        obj_info.into(),
-                    ));
                     vertices.clear();
                     indices.clear();
                 }
-                obj_info = tokens[1].split("-").collect::<Vec<&str>>()[0];
-                let aux = tokens[1].split("-").collect::<Vec<&str>>()[1];
-                let (x, y, z) = (
-                    aux.split(",").collect::<Vec<&str>>()[0]
-                        .parse::<f32>()
-                        .unwrap(),
-                    aux.split(",").collect::<Vec<&str>>()[1]
-                        .parse::<f32>()
-                        .unwrap(),
-                    aux.split(",").collect::<Vec<&str>>()[2]
-                        .parse::<f32>()
-                        .unwrap(),
+                header = Some(
+                    tokens
+                        .get(1)
+                        .and_then(|x| object_header(x))
+                        .ok_or_else(invalid)?,
                 );
-                obj_rotation = Vector3::new(y.to_radians(), z.to_radians(), x.to_radians());
-                body_type = match &obj_info[0..1] {
-                    "D" => Some(RigidBodyType::Dynamic),
-                    "F" => Some(RigidBodyType::Fixed),
-                    _ => panic!("wtf is that body type bruh."),
-                };
-                object_shape = match &obj_info[1..2] {
-                    "C" => Some(S::CONVEX),
-                    "M" => Some(S::MULTI),
-                    "S" => Some(S::SensorMulti),
-                    "B" => Some(S::SPHERE(Sphere::new(1.0))),
-                    _ => panic!("bruhhh."),
-                };
             }
             _ => continue,
         }
     }
-    let (obj_position, radius) = move_to_origin(&mut vertices);
+    let header = header.ok_or_else(|| ServerError::Scene(format!("{}: no objects", file_path)))?;
+    objects.push(build_object(header, &mut vertices, &indices)?);
+    Ok(objects)
+}
 
-    objects.push(Object::new(
-        object_shape.clone().unwrap(),
-        body_type.unwrap(),
+fn object_header(name: &str) -> Option<(&str, Vector3, RigidBodyType, S)> {
+    let (obj_info, aux) = name.split_once('-')?;
+    let angles = aux
+        .split(',')
+        .map(|x| x.parse::<f32>().ok())
+        .collect::<Option<Vec<f32>>>()?;
+    let [x, y, z] = angles[..] else {
+        return None;
+    };
+    let obj_rotation = Vector3::new(y.to_radians(), z.to_radians(), x.to_radians());
+    let body_type = match obj_info.get(0..1)? {
+        "D" => RigidBodyType::Dynamic,
+        "F" => RigidBodyType::Fixed,
+        _ => return None,
+    };
+    let object_shape = match obj_info.get(1..2)? {
+        "C" => S::CONVEX,
+        "M" => S::MULTI,
+        "S" => S::SensorMulti,
+        "B" => S::SPHERE(Sphere::new(1.0)),
+        _ => return None,
+    };
+    Some((obj_info, obj_rotation, body_type, object_shape))
+}
+
+fn build_object(
+    (obj_info, obj_rotation, body_type, object_shape): (&str, Vector3, RigidBodyType, S),
+    vertices: &mut Vec<OPoint<f32, Const<3>>>,
+    indices: &[[u32; 3]],
+) -> Result<Object, ServerError> {
+    if vertices.is_empty() {
+        return Err(ServerError::Scene(format!("{} has no vertices", obj_info)));
+    }
+    let (obj_position, radius) = move_to_origin(vertices);
+    Ok(Object::new(
+        object_shape,
+        body_type,
         vertices.clone(),
-        indices.clone(),
+        indices.to_vec(),
         obj_rotation,
         obj_position,
         radius,
         obj_info.into(),
-    ));
-    vertices.clear();
-    indices.clear();
-    objects
+    ))
+}
+
+fn token<T: std::str::FromStr>(tokens: &[&str], index: usize) -> Option<T> {
+    tokens.get(index)?.parse::<T>().ok()
 }
 
-fn split(data: &str) -> u32 {
-    data.split("/").collect::<Vec<&str>>()[0]
-        .parse::<u32>()
-        .unwrap()
-        - 1
+fn split(data: &str) -> Option<u32> {
+    data.split('/').next()?.parse::<u32>().ok()?.checked_sub(1)
 }
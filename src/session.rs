@@ -1,33 +1,87 @@
+use crate::game::{PlayerUpdate, DEFAULT_TICK_RATE};
 use crate::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+pub const MAX_CATCHUP_TICKS: u32 = 5;
+pub const INPUT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const MIN_PLAYERS: usize = 2;
+pub const COUNTDOWN_TIME: f32 = 10.0;
+pub const INTERMISSION_TIME: f32 = 15.0;
+pub const MAP_ROTATION: &[&str] = &["static/models/scene.obj"];
+pub const MAX_PAGE_SIZE: u8 = 32;
 
 pub struct JoinPlayer {
-    pub player: Player,
-    pub sender: Sender<Player>,
-    pub receiver: Receiver<(Player, ResponseSignal)>,
-    pub dt: f32,
-    pub stream: TcpStream,
+    pub player_id: u64,
+    pub inputs: Sender<(u64, PlayerSignal)>,
+    pub leaves: Sender<(u64, LeaveReason)>,
+    pub updates: UnboundedReceiver<PlayerUpdate>,
+    pub link: PlayerLink,
 }
 
 impl JoinPlayer {
     pub fn new(
-        player: Player,
-        sender: Sender<Player>,
-        receiver: Receiver<(Player, ResponseSignal)>,
-        dt: f32,
-        stream: TcpStream,
+        player_id: u64,
+        inputs: Sender<(u64, PlayerSignal)>,
+        leaves: Sender<(u64, LeaveReason)>,
+        updates: UnboundedReceiver<PlayerUpdate>,
+        link: PlayerLink,
     ) -> Self {
         Self {
-            player,
-            sender,
-            receiver,
-            dt,
-            stream,
+            player_id,
+            inputs,
+            leaves,
+            updates,
+            link,
         }
     }
+
+    pub async fn run(mut self) {
+        let mut deadline = Instant::now() + INPUT_TIMEOUT;
+        let reason = loop {
+            tokio::select! {
+                input = self.link.recv() => {
+                    let Some(input) = input else {
+                        break LeaveReason::Disconnected;
+                    };
+                    deadline = Instant::now() + INPUT_TIMEOUT;
+                    if self.inputs.send((self.player_id, input)).is_err() {
+                        break LeaveReason::Disconnected;
+                    }
+                }
+                update = self.updates.recv() => {
+                    let Some(update) = update else {
+                        break LeaveReason::Disconnected;
+                    };
+                    if !update.table.entries.is_empty() {
+                        let _ = self.link.send(&update.table).await;
+                    }
+                    if let Some(status) = update.status {
+                        let _ = self.link.send(&status).await;
+                    }
+                    if let Some(scoreboard) = update.scoreboard {
+                        let _ = self.link.send(&scoreboard).await;
+                    }
+                    for left in update.left.iter() {
+                        let _ = self.link.send(left).await;
+                    }
+                    let _ = update.snapshot.send(&mut self.link).await;
+                }
+                _ = tokio::time::sleep_until(deadline) => break LeaveReason::TimedOut,
+            }
+        };
+        println!("player {} left: {:?}", self.player_id, reason);
+        let _ = self.leaves.send((self.player_id, reason));
+    }
 }
 
 #[derive(Clone, Debug, DekuRead, DekuWrite)]
 pub struct NewSessionRequest {
+    pub version: u16,
+    pub capabilities: u32,
     #[deku(update = "self.id.len()")]
     id_count: usize,
     #[deku(count = "id_count")]
@@ -37,22 +91,56 @@ pub struct NewSessionRequest {
     #[deku(count = "count")]
     pub password: Vec<u8>,
     pub player_limit: u8,
+    pub encoding: SnapshotEncoding,
+    #[deku(cond = "*version >= 2", default = "DEFAULT_TICK_RATE")]
+    pub tick_rate: u8,
+    #[deku(cond = "*version >= 2", default = "ModeKind::Deathmatch")]
+    pub mode: ModeKind,
+    #[deku(cond = "*version >= 2", default = "false")]
+    pub friendly_fire: bool,
+    #[deku(cond = "*version >= 2", default = "*count == 0")]
+    pub public: bool,
+    #[deku(cond = "*version >= 2", default = "0")]
+    pub reserved_slots: u8,
+    #[deku(cond = "*version >= 2", default = "0")]
+    pub spectator_limit: u8,
+    #[deku(
+        cond = "*version >= 2",
+        default = "0",
+        update = "self.admin_password.len()"
+    )]
+    admin_count: usize,
+    #[deku(cond = "*version >= 2", count = "admin_count")]
+    pub admin_password: Vec<u8>,
 }
 
 impl NewSessionRequest {
     pub fn new(id: &str, password: &str) -> Self {
         Self {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES,
             id_count: id.len(),
             id: id.as_bytes().to_vec(),
             count: password.len(),
             password: password.as_bytes().to_vec(),
             player_limit: 8,
+            encoding: SnapshotEncoding::Full,
+            tick_rate: DEFAULT_TICK_RATE,
+            mode: ModeKind::Deathmatch,
+            friendly_fire: false,
+            public: password.is_empty(),
+            reserved_slots: 0,
+            spectator_limit: 0,
+            admin_count: 0,
+            admin_password: Vec::new(),
         }
     }
 }
 
 #[derive(Debug, DekuRead, DekuWrite)]
 pub struct JoinSessionRequest {
+    pub version: u16,
+    pub capabilities: u32,
     #[deku(update = "self.id.len()")]
     id_count: usize,
     #[deku(count = "id_count")]
@@ -61,15 +149,20 @@ pub struct JoinSessionRequest {
     count: usize,
     #[deku(count = "count")]
     pub password: Vec<u8>,
+    #[deku(cond = "*version >= 2", default = "TEAM_NONE")]
+    pub team: u8,
 }
 
 impl JoinSessionRequest {
     pub fn new(id: &str, password: &str) -> Self {
         Self {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES,
             id_count: id.len(),
             id: id.as_bytes().to_vec(),
             count: password.len(),
             password: password.as_bytes().to_vec(),
+            team: TEAM_NONE,
         }
     }
 }
@@ -81,13 +174,138 @@ pub enum ServerRequest {
     NewSession(NewSessionRequest),
     #[deku(id = "0x2")]
     JoinSession(JoinSessionRequest),
+    #[deku(id = "0x3")]
+    ListSessions(ListSessionsRequest),
+}
+
+#[derive(Debug, DekuRead, DekuWrite)]
+pub struct ListSessionsRequest {
+    pub version: u16,
+    #[deku(update = "self.mode.is_some()")]
+    has_mode: bool,
+    #[deku(cond = "*has_mode")]
+    pub mode: Option<ModeKind>,
+    pub hide_locked: bool,
+    pub hide_full: bool,
+    pub page: u16,
+    pub page_size: u8,
+}
+
+impl ListSessionsRequest {
+    pub fn new(mode: Option<ModeKind>, page: u16) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            has_mode: mode.is_some(),
+            mode,
+            hide_locked: false,
+            hide_full: false,
+            page,
+            page_size: MAX_PAGE_SIZE,
+        }
+    }
+
+    pub fn matches(&self, info: &SessionInfo) -> bool {
+        self.mode.map_or(true, |x| x == info.mode)
+            && !(self.hide_locked && info.locked)
+            && !(self.hide_full && info.full())
+    }
+}
+
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+pub struct SessionInfo {
+    #[deku(update = "self.id.len()")]
+    id_count: u8,
+    #[deku(count = "id_count")]
+    pub id: Vec<u8>,
+    pub mode: ModeKind,
+    #[deku(update = "self.map.len()")]
+    map_len: u8,
+    #[deku(count = "map_len")]
+    pub map: Vec<u8>,
+    pub players: u8,
+    pub player_limit: u8,
+    pub spectators: u8,
+    pub locked: bool,
+    pub tick_rate: u8,
+}
+
+impl SessionInfo {
+    pub fn new(id: &str, request: &NewSessionRequest) -> Self {
+        let mut info = Self {
+            id_count: id.len() as u8,
+            id: id.as_bytes().to_vec(),
+            mode: request.mode,
+            map_len: 0,
+            map: Vec::new(),
+            players: 0,
+            player_limit: request.player_limit,
+            spectators: 0,
+            locked: !request.public,
+            tick_rate: request.tick_rate,
+        };
+        info.set_map(MAP_ROTATION[0]);
+        info
+    }
+
+    pub fn set_map(&mut self, map: &str) {
+        let map = &map.as_bytes()[..map.len().min(u8::MAX as usize)];
+        if self.map != map {
+            self.map = map.to_vec();
+            self.map_len = self.map.len() as u8;
+        }
+    }
+
+    pub fn full(&self) -> bool {
+        self.players >= self.player_limit
+    }
+}
+
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+pub struct SessionList {
+    pub total: u16,
+    pub page: u16,
+    #[deku(update = "self.sessions.len()")]
+    count: u8,
+    #[deku(count = "count")]
+    pub sessions: Vec<SessionInfo>,
+}
+
+impl SessionList {
+    pub fn new(mut sessions: Vec<SessionInfo>, page: u16, page_size: u8) -> Self {
+        sessions.sort_by(|a, b| a.id.cmp(&b.id));
+        let page_size = match page_size {
+            0 => MAX_PAGE_SIZE,
+            x => x.min(MAX_PAGE_SIZE),
+        } as usize;
+        let total = sessions.len().min(u16::MAX as usize) as u16;
+        let sessions = sessions
+            .into_iter()
+            .skip(page as usize * page_size)
+            .take(page_size)
+            .collect::<Vec<SessionInfo>>();
+        Self {
+            total,
+            page,
+            count: sessions.len() as u8,
+            sessions,
+        }
+    }
+}
+
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+pub struct JoinInfo {
+    pub token: u64,
+    pub version: u16,
+    pub udp_port: u16,
+    #[deku(cond = "*version >= 2", default = "Bounds::default()")]
+    pub bounds: Bounds,
 }
 
 #[derive(DekuRead, DekuWrite)]
 #[deku(type = "u8")]
 pub enum JoinResponse {
     #[deku(id = "0x1")]
-    Ok,
+    Ok(JoinInfo),
     #[deku(id = "0x2")]
     Err(Reason),
 }
@@ -95,91 +313,414 @@ pub enum JoinResponse {
 pub struct Session {
     pub id: String,
     pub game_manager: GameManager,
-    password: String,
-    player_limit: u8,
-    sender: Sender<(JoinResponse, Option<JoinPlayer>)>,
-    receiver: Receiver<(JoinSessionRequest, TcpStream)>,
-    manager_receiver: Receiver<GameManager>,
-    manager_sender: Sender<GameManager>,
+    pub socket: SessionSocket,
+    pub admins: HashSet<u64>,
+    pub info: Arc<Mutex<SessionInfo>>,
+    seats: Seats,
+    receiver: UnboundedReceiver<JoinRequest>,
+    manager_receiver: Receiver<(usize, Result<GameManager, ServerError>)>,
+    manager_sender: Sender<(usize, Result<GameManager, ServerError>)>,
+    closed: UnboundedSender<String>,
+    rotation: Vec<String>,
+    map: usize,
+    loading: bool,
 }
 
 impl Session {
     pub fn new(
         request: NewSessionRequest,
-        receiver: Receiver<(JoinSessionRequest, TcpStream)>,
+        receiver: UnboundedReceiver<JoinRequest>,
         mut game_manager: GameManager,
-    ) -> Result<(Self, Receiver<(JoinResponse, Option<JoinPlayer>)>), Reason> {
-        let (sender, response_receiver) = unbounded();
+        socket: SessionSocket,
+        closed: UnboundedSender<String>,
+    ) -> Result<Self, ServerError> {
         let (manager_sender, manager_receiver) = unbounded();
         let (new_sender, new_receiver) = unbounded();
-        let (nsender, nreceiver) = unbounded();
-        game_manager.nsender = nsender;
-        game_manager.nreceiver = nreceiver;
         game_manager.sender = new_sender;
         game_manager.receiver = new_receiver;
-        if let Ok(password) = String::from_utf8(request.password) {
-            return Ok((
-                Self {
-                    id: String::from_utf8(request.id).unwrap(),
-                    player_limit: request.player_limit,
-                    game_manager,
-                    password,
-                    receiver,
-                    sender,
-                    manager_receiver,
-                    manager_sender,
-                },
-                response_receiver,
-            ));
-        } else {
-            return Err(Reason::InvalidPassword);
+        let (leave_sender, leave_receiver) = unbounded();
+        game_manager.leave_sender = leave_sender;
+        game_manager.leave_receiver = leave_receiver;
+        let (collision_sender, collision_receiver) = unbounded();
+        let (contact_sender, contact_receiver) = unbounded();
+        game_manager.collision_sender = collision_sender;
+        game_manager.collision_receiver = collision_receiver;
+        game_manager.contact_sender = contact_sender;
+        game_manager.contact_receiver = contact_receiver;
+        game_manager.outbound = HashMap::new();
+        game_manager.encoding = request.encoding;
+        game_manager.set_tick_rate(request.tick_rate);
+        game_manager.mode = request.mode.create(&game_manager.flag_bases);
+        game_manager.friendly_fire = request.friendly_fire;
+        let id = parse_id(&request.id)?;
+        let info = SessionInfo::new(&id, &request);
+        let session = Self {
+            id,
+            admins: HashSet::new(),
+            info: Arc::new(Mutex::new(info)),
+            seats: Seats::new(&request),
+            game_manager,
+            socket,
+            receiver,
+            manager_receiver,
+            manager_sender,
+            closed,
+            rotation: MAP_ROTATION.iter().map(|x| x.to_string()).collect(),
+            map: 0,
+            loading: false,
+        };
+        session.publish();
+        Ok(session)
+    }
+
+    pub async fn run(mut self) {
+        let listener = tokio::spawn(self.socket.clone().listen());
+        let mut pipeline = PhysicsPipeline::new();
+        let mut interval = tick_interval(self.game_manager.dt);
+        let mut last = Instant::now();
+        let mut accumulator = 0.0;
+        loop {
+            interval.tick().await;
+            self.update().await;
+            if self.game_manager.players.is_empty() {
+                break;
+            }
+            let dt = self.game_manager.dt;
+            if interval.period() != Duration::from_secs_f32(dt) {
+                interval = tick_interval(dt);
+            }
+            let now = Instant::now();
+            accumulator += now.duration_since(last).as_secs_f32();
+            last = now;
+            let mut steps = 0;
+            while accumulator >= dt && steps < MAX_CATCHUP_TICKS {
+                self.game_manager.tick(&mut pipeline);
+                self.update_phase(dt);
+                accumulator -= dt;
+                steps += 1;
+            }
+            if accumulator >= dt {
+                accumulator = 0.0;
+            }
         }
+        println!("session {} is empty, shutting down", self.id);
+        if self.game_manager.phase == MatchPhase::InProgress {
+            self.end_match();
+        }
+        listener.abort();
+        self.receiver.close();
+        while let Ok(mut join) = self.receiver.try_recv() {
+            let response = JoinResponse::Err(Reason::IdDoesntExist);
+            let _ = write_message(&mut join.stream, &response).await;
+            let _ = join.reply.send(Err(Reason::IdDoesntExist));
+        }
+        let _ = self.closed.send(self.id.clone());
     }
 
-    pub async fn update(
-        &mut self,
-        pipeline: &mut PhysicsPipeline,
-        instant: &mut tokio::time::Instant,
-    ) {
-        if !self.manager_receiver.is_empty() {
-            self.game_manager = self.manager_receiver.recv().unwrap();
+    pub async fn update(&mut self) {
+        if let Ok((map, loaded)) = self.manager_receiver.try_recv() {
+            match loaded {
+                Ok(manager) => {
+                    let previous = std::mem::replace(&mut self.game_manager, manager);
+                    self.game_manager.adopt(previous);
+                    self.map = map;
+                }
+                Err(err) => {
+                    println!("failed to load {}: {}", self.rotation[map], err);
+                    self.set_phase(MatchPhase::Warmup, 0.0);
+                }
+            }
+            self.loading = false;
         }
-        while !self.receiver.is_empty() {
-            self.join_player().await;
+        while let Ok((id, reason)) = self.game_manager.leave_receiver.try_recv() {
+            self.game_manager.remove_player(&id, reason);
+            self.admins.remove(&id);
         }
-        self.game_manager.update(pipeline, instant).await;
+        while let Ok(request) = self.receiver.try_recv() {
+            self.join_player(request).await;
+        }
+        self.publish();
     }
 
-    async fn join_player(&mut self) {
-        println!("joining player");
-        let (request, mut stream) = self.receiver.recv().unwrap();
-        if request.password != self.password.as_bytes() {
-            stream
-                .write(&JoinResponse::Err(Reason::WrongPassword).to_bytes().unwrap())
-                .await
-                .unwrap();
-            self.sender
-                .send((JoinResponse::Err(Reason::WrongPassword), None))
-                .unwrap();
-            return;
-        }
-        let player = self.game_manager.new_player();
-        stream
-            .write(&JoinResponse::Ok.to_bytes().unwrap())
+    fn publish(&self) {
+        let players = self.game_manager.player_count();
+        let spectators = self.game_manager.players.len() - players;
+        let capacity = self.seats.capacity(self.seated_admins());
+        let mut info = self.info.lock().unwrap();
+        info.players = players.min(u8::MAX as usize) as u8;
+        info.player_limit = capacity;
+        info.spectators = spectators.min(u8::MAX as usize) as u8;
+        info.tick_rate = (1.0 / self.game_manager.dt).round() as u8;
+        info.set_map(&self.game_manager.scene);
+    }
+
+    fn set_phase(&mut self, phase: MatchPhase, time: f32) {
+        self.game_manager.phase = phase;
+        self.game_manager.phase_time = time;
+    }
+
+    fn update_phase(&mut self, dt: f32) {
+        use MatchPhase::*;
+
+        let manager = &mut self.game_manager;
+        manager.phase_time = (manager.phase_time - dt).max(0.0);
+        let players = manager.player_count();
+        let expired = manager.phase_time <= 0.0;
+        let decided = manager.mode.winner() != Winner::Undecided;
+        match manager.phase {
+            Warmup if players >= MIN_PLAYERS => self.set_phase(Countdown, COUNTDOWN_TIME),
+            Countdown if players < MIN_PLAYERS => self.set_phase(Warmup, 0.0),
+            Countdown if expired => {
+                self.game_manager.restart_match();
+                self.set_phase(InProgress, 0.0);
+            }
+            InProgress if decided => {
+                self.set_phase(Intermission, INTERMISSION_TIME);
+                self.end_match();
+            }
+            Intermission if expired && !self.loading => self.load_next_map(),
+            _ => {}
+        }
+    }
+
+    fn end_match(&mut self) {
+        self.game_manager.end_match();
+        let summary = self.game_manager.summary(&self.id);
+        tokio::task::spawn_blocking(move || match summary.write() {
+            Ok(path) => println!("match summary written to {}", path),
+            Err(err) => println!("failed to write match summary: {}", err),
+        });
+    }
+
+    fn load_next_map(&mut self) {
+        self.loading = true;
+        let map = (self.map + 1) % self.rotation.len();
+        let scene = self.rotation[map].clone();
+        let sender = self.manager_sender.clone();
+        let mode = self.game_manager.mode.kind();
+        tokio::spawn(async move {
+            let path = scene.clone();
+            let loaded = tokio::task::spawn_blocking(move || {
+                let mut manager = GameManager::new();
+                manager.init_scene(&scene)?;
+                if !mode.supported(&manager.flag_bases) {
+                    let err = format!("{} has no flag bases for {:?}", scene, mode);
+                    return Err(ServerError::Scene(err));
+                }
+                manager.loadout = load_loadout(WEAPONS_PATH)?;
+                Ok(manager)
+            })
             .await
-            .unwrap();
-        stream.flush().await.unwrap();
-        let (sender, receiver) = (
-            self.game_manager.sender.clone(),
-            self.game_manager.nreceiver.clone(),
-        );
-        let dt = self.game_manager.dt;
-        self.sender
-            .send((
-                JoinResponse::Ok,
-                Some(JoinPlayer::new(player, sender, receiver, dt, stream)),
-            ))
-            .unwrap();
-        println!("player joined!");
+            .unwrap_or_else(|err| Err(ServerError::Scene(format!("{}: {}", path, err))));
+            let _ = sender.send((map, loaded));
+        });
+    }
+
+    async fn join_player(&mut self, join: JoinRequest) {
+        println!("joining player");
+        let reply = join.reply;
+        match self.admit(join.request, join.stream, join.admin).await {
+            Ok(joined) => {
+                if let Err(Ok(joined)) = reply.send(Ok(joined)) {
+                    self.game_manager
+                        .remove_player(&joined.player_id, LeaveReason::Disconnected);
+                    return;
+                }
+                println!("player joined!");
+            }
+            Err(err) => {
+                println!("player rejected: {}", err);
+                let _ = reply.send(Err(err.reason()));
+            }
+        }
+    }
+
+    async fn admit(
+        &mut self,
+        request: JoinSessionRequest,
+        mut stream: TcpStream,
+        admin: bool,
+    ) -> Result<JoinPlayer, ServerError> {
+        let version = negotiate(request.version).ok_or(Reason::IncompatibleVersion)?;
+        let Some(spectator) = self.seat(version, admin) else {
+            write_message(&mut stream, &JoinResponse::Err(Reason::SessionFull)).await?;
+            return Err(Reason::SessionFull.into());
+        };
+        let mut link = self.socket.register(stream, version, request.capabilities);
+        write_message(
+            &mut link.control,
+            &JoinResponse::Ok(link.join_info(self.game_manager.bounds)),
+        )
+        .await?;
+        let team = (request.team != TEAM_NONE).then_some(request.team);
+        let (player, updates) =
+            self.game_manager
+                .new_player(version, request.capabilities, team, spectator);
+        if admin {
+            self.admins.insert(player.id);
+        }
+        let inputs = self.game_manager.sender.clone();
+        let leaves = self.game_manager.leave_sender.clone();
+        Ok(JoinPlayer::new(player.id, inputs, leaves, updates, link))
+    }
+
+    fn seated_admins(&self) -> usize {
+        let players = &self.game_manager.players;
+        self.admins
+            .iter()
+            .filter(|x| players.get(x).map_or(false, |x| !x.spectating()))
+            .count()
+    }
+
+    fn seat(&self, version: u16, admin: bool) -> Option<bool> {
+        let active = self.game_manager.player_count();
+        let spectators = self.game_manager.players.len() - active;
+        self.seats
+            .seat(active, spectators, self.seated_admins(), version, admin)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Seats {
+    pub player_limit: u8,
+    pub reserved_slots: u8,
+    pub spectator_limit: u8,
+}
+
+impl Seats {
+    pub fn new(request: &NewSessionRequest) -> Self {
+        Self {
+            player_limit: request.player_limit,
+            reserved_slots: request.reserved_slots.min(request.player_limit),
+            spectator_limit: request.spectator_limit,
+        }
+    }
+
+    fn unfilled(&self, seated_admins: usize) -> usize {
+        (self.reserved_slots as usize).saturating_sub(seated_admins)
+    }
+
+    pub fn capacity(&self, seated_admins: usize) -> u8 {
+        (self.player_limit as usize - self.unfilled(seated_admins)) as u8
+    }
+
+    pub fn seat(
+        &self,
+        active: usize,
+        spectators: usize,
+        seated_admins: usize,
+        version: u16,
+        admin: bool,
+    ) -> Option<bool> {
+        let reserved = if admin {
+            0
+        } else {
+            self.unfilled(seated_admins)
+        };
+        if active + reserved < self.player_limit as usize {
+            return Some(false);
+        }
+        (version >= 2 && spectators < self.spectator_limit as usize).then_some(true)
+    }
+}
+
+fn tick_interval(dt: f32) -> Interval {
+    let mut interval = tokio::time::interval(Duration::from_secs_f32(dt));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seats(player_limit: u8, reserved_slots: u8, spectator_limit: u8) -> Seats {
+        let mut request = NewSessionRequest::new("seats", "");
+        request.player_limit = player_limit;
+        request.reserved_slots = reserved_slots;
+        request.spectator_limit = spectator_limit;
+        Seats::new(&request)
+    }
+
+    #[test]
+    fn reserved_slots_are_held_for_admins() {
+        let seats = seats(4, 2, 0);
+        assert_eq!(seats.seat(1, 0, 0, PROTOCOL_VERSION, false), Some(false));
+        assert_eq!(seats.seat(2, 0, 0, PROTOCOL_VERSION, false), None);
+        assert_eq!(seats.seat(2, 0, 0, PROTOCOL_VERSION, true), Some(false));
+        assert_eq!(seats.seat(3, 0, 1, PROTOCOL_VERSION, false), None);
+        assert_eq!(seats.seat(2, 0, 1, PROTOCOL_VERSION, false), Some(false));
+        assert_eq!(seats.seat(4, 0, 2, PROTOCOL_VERSION, true), None);
+    }
+
+    #[test]
+    fn reserved_slots_are_capped_by_player_limit() {
+        let seats = seats(2, 5, 0);
+        assert_eq!(seats.reserved_slots, 2);
+        assert_eq!(seats.seat(0, 0, 0, PROTOCOL_VERSION, false), None);
+        assert_eq!(seats.seat(1, 0, 0, PROTOCOL_VERSION, true), Some(false));
+    }
+
+    #[test]
+    fn full_sessions_fall_back_to_spectating() {
+        let seats = seats(2, 0, 1);
+        assert_eq!(seats.seat(2, 0, 0, PROTOCOL_VERSION, false), Some(true));
+        assert_eq!(seats.seat(2, 1, 0, PROTOCOL_VERSION, false), None);
+        assert_eq!(seats.seat(2, 0, 0, MIN_PROTOCOL_VERSION, false), None);
+    }
+
+    #[test]
+    fn capacity_excludes_unfilled_reserved_slots() {
+        let seats = seats(4, 2, 0);
+        assert_eq!(seats.capacity(0), 2);
+        assert_eq!(seats.capacity(1), 3);
+        assert_eq!(seats.capacity(3), 4);
+
+        let mut info = SessionInfo::new("full", &NewSessionRequest::new("full", ""));
+        info.player_limit = seats.capacity(0);
+        info.players = 2;
+        assert!(info.full());
+        info.player_limit = seats.capacity(1);
+        assert!(!info.full());
+    }
+
+    fn listed(count: usize) -> Vec<SessionInfo> {
+        (0..count)
+            .rev()
+            .map(|x| {
+                let id = format!("session-{:02}", x);
+                SessionInfo::new(&id, &NewSessionRequest::new(&id, ""))
+            })
+            .collect()
+    }
+
+    fn ids(list: &SessionList) -> Vec<String> {
+        list.sessions
+            .iter()
+            .map(|x| String::from_utf8(x.id.clone()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn session_list_pages_in_id_order() {
+        let list = SessionList::new(listed(5), 0, 2);
+        assert_eq!(list.total, 5);
+        assert_eq!(ids(&list), vec!["session-00", "session-01"]);
+        let list = SessionList::new(listed(5), 2, 2);
+        assert_eq!(ids(&list), vec!["session-04"]);
+        let list = SessionList::new(listed(5), 3, 2);
+        assert_eq!((list.total, list.sessions.len()), (5, 0));
+    }
+
+    #[test]
+    fn session_list_clamps_page_size() {
+        let count = MAX_PAGE_SIZE as usize + 8;
+        let list = SessionList::new(listed(count), 0, 0);
+        assert_eq!(list.sessions.len(), MAX_PAGE_SIZE as usize);
+        let list = SessionList::new(listed(count), 0, u8::MAX);
+        assert_eq!(list.sessions.len(), MAX_PAGE_SIZE as usize);
+        let list = SessionList::new(listed(count), 1, u8::MAX);
+        assert_eq!(list.sessions.len(), 8);
+        assert_eq!(list.total as usize, count);
     }
 }
@@ -0,0 +1,217 @@
+use crate::*;
+use std::collections::{HashMap, VecDeque};
+
+pub const POSITION_THRESHOLD: f32 = 0.001;
+pub const ROTATION_THRESHOLD: f32 = 0.0005;
+pub const SNAPSHOT_HISTORY: usize = 64;
+
+#[derive(Clone, Default)]
+pub struct WorldState {
+    pub objects: HashMap<EntityId, NetworkObject>,
+    pub players: HashMap<EntityId, ResponseSignal>,
+}
+
+impl WorldState {
+    pub fn new(objects: &[NetworkObject], players: &[ResponseSignal]) -> Self {
+        Self {
+            objects: objects.iter().map(|x| (x.id, x.clone())).collect(),
+            players: players.iter().map(|x| (x.id, x.clone())).collect(),
+        }
+    }
+
+    pub fn delta(
+        &self,
+        baseline: Option<&WorldState>,
+    ) -> (Vec<NetworkObject>, Vec<ResponseSignal>, Vec<EntityId>) {
+        let Some(baseline) = baseline else {
+            return (
+                self.objects.values().cloned().collect(),
+                self.players.values().cloned().collect(),
+                Vec::new(),
+            );
+        };
+        let objects = self
+            .objects
+            .values()
+            .filter(|x| match baseline.objects.get(&x.id) {
+                Some(previous) => object_changed(x, previous),
+                None => true,
+            })
+            .cloned()
+            .collect();
+        let players = self
+            .players
+            .values()
+            .filter(|x| match baseline.players.get(&x.id) {
+                Some(previous) => player_changed(x, previous),
+                None => true,
+            })
+            .cloned()
+            .collect();
+        let removed = baseline
+            .objects
+            .keys()
+            .filter(|x| !self.objects.contains_key(x))
+            .chain(
+                baseline
+                    .players
+                    .keys()
+                    .filter(|x| !self.players.contains_key(x)),
+            )
+            .copied()
+            .collect();
+        (objects, players, removed)
+    }
+}
+
+fn moved(current: &[f32], previous: &[f32], threshold: f32) -> bool {
+    current
+        .iter()
+        .zip(previous)
+        .any(|(a, b)| (a - b).abs() > threshold)
+}
+
+fn object_changed(current: &NetworkObject, previous: &NetworkObject) -> bool {
+    moved(&current.position, &previous.position, POSITION_THRESHOLD)
+        || moved(&current.rotation, &previous.rotation, ROTATION_THRESHOLD)
+}
+
+fn player_changed(current: &ResponseSignal, previous: &ResponseSignal) -> bool {
+    let positions = [
+        (current.translation, previous.translation),
+        (current.camera_pos, previous.camera_pos),
+        (current.camera_target, previous.camera_target),
+    ];
+    positions
+        .iter()
+        .any(|(a, b)| moved(a, b, POSITION_THRESHOLD))
+        || moved(&current.fwd, &previous.fwd, ROTATION_THRESHOLD)
+        || moved(&current.right, &previous.right, ROTATION_THRESHOLD)
+        || current.health != previous.health
+        || current.armour != previous.armour
+        || current.state != previous.state
+        || current.team != previous.team
+}
+
+#[derive(Clone, Default)]
+pub struct SnapshotHistory {
+    last: u32,
+    sent: VecDeque<(u32, WorldState)>,
+}
+
+impl SnapshotHistory {
+    pub fn baseline(&self, ack: u32) -> Option<&(u32, WorldState)> {
+        if ack == 0 {
+            return None;
+        }
+        self.sent.iter().find(|x| x.0 == ack)
+    }
+
+    pub fn latest(&self) -> u32 {
+        self.last
+    }
+
+    pub fn push(&mut self, state: WorldState) -> u32 {
+        self.last = self.last.wrapping_add(1).max(1);
+        if self.sent.len() >= SNAPSHOT_HISTORY {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((self.last, state));
+        self.last
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Snapshot {
+    Full(ResponseSignal),
+    Quantized(QuantizedSignal),
+}
+
+impl Snapshot {
+    pub fn new(signal: ResponseSignal, encoding: SnapshotEncoding, bounds: &Bounds) -> Self {
+        match encoding {
+            SnapshotEncoding::Full => Snapshot::Full(signal),
+            SnapshotEncoding::Quantized => {
+                Snapshot::Quantized(QuantizedSignal::new(&signal, bounds))
+            }
+        }
+    }
+
+    pub async fn send(&self, link: &mut PlayerLink) -> std::io::Result<()> {
+        match self {
+            Snapshot::Full(signal) => link.send(signal).await,
+            Snapshot::Quantized(signal) => link.send(signal).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(id: EntityId, x: f32) -> NetworkObject {
+        NetworkObject {
+            id,
+            position: [x, 0.0, 0.0],
+            rotation: [0.0, 0.0, 0.0, 1.0],
+        }
+    }
+
+    fn player(id: EntityId, health: u8) -> ResponseSignal {
+        let mut signal = ResponseSignal::default();
+        signal.id = id;
+        signal.health = health;
+        signal
+    }
+
+    fn ids<T>(items: &[T], id: impl Fn(&T) -> EntityId) -> Vec<EntityId> {
+        let mut ids = items.iter().map(id).collect::<Vec<EntityId>>();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn full_state_without_baseline() {
+        let state = WorldState::new(&[object(1, 0.0), object(2, 0.0)], &[player(3, 100)]);
+        let (objects, players, removed) = state.delta(None);
+        assert_eq!(ids(&objects, |x| x.id), vec![1, 2]);
+        assert_eq!(ids(&players, |x| x.id), vec![3]);
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn delta_sends_changes_additions_and_removals() {
+        let baseline = WorldState::new(
+            &[object(1, 0.0), object(2, 0.0), object(3, 0.0)],
+            &[player(10, 100), player(11, 100)],
+        );
+        let state = WorldState::new(
+            &[
+                object(1, POSITION_THRESHOLD / 2.0),
+                object(2, 1.0),
+                object(4, 0.0),
+            ],
+            &[player(10, 50), player(12, 100)],
+        );
+        let (objects, players, mut removed) = state.delta(Some(&baseline));
+        removed.sort();
+        assert_eq!(ids(&objects, |x| x.id), vec![2, 4]);
+        assert_eq!(ids(&players, |x| x.id), vec![10, 12]);
+        assert_eq!(removed, vec![3, 11]);
+    }
+
+    #[test]
+    fn history_keeps_bounded_baselines() {
+        let mut history = SnapshotHistory::default();
+        assert!(history.baseline(0).is_none());
+        let first = history.push(WorldState::default());
+        assert_eq!(first, 1);
+        assert!(history.baseline(first).is_some());
+        for _ in 0..SNAPSHOT_HISTORY {
+            history.push(WorldState::default());
+        }
+        assert!(history.baseline(first).is_none());
+        assert_eq!(history.latest(), SNAPSHOT_HISTORY as u32 + 1);
+        assert!(history.baseline(history.latest()).is_some());
+    }
+}
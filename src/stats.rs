@@ -0,0 +1,125 @@
+use crate::*;
+use serde::Serialize;
+
+pub const SUMMARY_DIR: &str = "summaries";
+pub const PING_SMOOTHING: f32 = 0.1;
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PlayerStats {
+    pub entity: EntityId,
+    pub team: Option<u8>,
+    pub kills: u16,
+    pub deaths: u16,
+    pub assists: u16,
+    pub damage: f32,
+    pub shots: u32,
+    pub hits: u32,
+    pub ping: f32,
+}
+
+impl PlayerStats {
+    pub fn new(entity: EntityId, team: Option<u8>) -> Self {
+        Self {
+            entity,
+            team,
+            ..Default::default()
+        }
+    }
+
+    pub fn sample_ping(&mut self, ping: f32) {
+        if self.ping == 0.0 {
+            self.ping = ping;
+        } else {
+            self.ping += (ping - self.ping) * PING_SMOOTHING;
+        }
+    }
+
+    pub fn accuracy(&self) -> f32 {
+        if self.shots == 0 {
+            0.0
+        } else {
+            self.hits as f32 / self.shots as f32
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, DekuRead, DekuWrite)]
+pub struct ScoreEntry {
+    pub id: EntityId,
+    pub team: u8,
+    pub kills: u16,
+    pub deaths: u16,
+    pub assists: u16,
+    pub damage: u32,
+    pub shots: u32,
+    pub hits: u32,
+    pub ping: u16,
+}
+
+impl ScoreEntry {
+    pub fn new(stats: &PlayerStats) -> Self {
+        Self {
+            id: stats.entity,
+            team: stats.team.unwrap_or(TEAM_NONE),
+            kills: stats.kills,
+            deaths: stats.deaths,
+            assists: stats.assists,
+            damage: stats.damage.round() as u32,
+            shots: stats.shots,
+            hits: stats.hits,
+            ping: stats.ping.round().min(u16::MAX as f32) as u16,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, DekuRead, DekuWrite)]
+pub struct Scoreboard {
+    #[deku(update = "self.entries.len()")]
+    count: u8,
+    #[deku(count = "count")]
+    pub entries: Vec<ScoreEntry>,
+}
+
+impl Scoreboard {
+    pub fn new(mut entries: Vec<ScoreEntry>) -> Self {
+        entries.sort_by(|a, b| b.kills.cmp(&a.kills).then(a.deaths.cmp(&b.deaths)));
+        entries.truncate(u8::MAX as usize);
+        Self {
+            count: entries.len() as u8,
+            entries,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlayerSummary {
+    pub player: u64,
+    #[serde(flatten)]
+    pub stats: PlayerStats,
+    pub accuracy: f32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MatchSummary {
+    pub session: String,
+    pub map: String,
+    pub mode: String,
+    pub winner: String,
+    pub ended_at: u64,
+    pub players: Vec<PlayerSummary>,
+}
+
+impl MatchSummary {
+    pub fn write(&self) -> std::io::Result<String> {
+        std::fs::create_dir_all(SUMMARY_DIR)?;
+        let name = self
+            .session
+            .chars()
+            .map(|x| if x.is_ascii_alphanumeric() { x } else { '_' })
+            .collect::<String>();
+        let path = format!("{}/{}-{}.json", SUMMARY_DIR, name, self.ended_at);
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(&path, json)?;
+        Ok(path)
+    }
+}
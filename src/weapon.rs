@@ -0,0 +1,152 @@
+use crate::*;
+use serde::Deserialize;
+
+pub const WEAPONS_PATH: &str = "static/models/weapons.toml";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FireMode {
+    Hitscan,
+    Projectile,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WeaponDef {
+    pub name: String,
+    pub mode: FireMode,
+    pub damage: f32,
+    pub fire_rate: f32,
+    #[serde(default)]
+    pub spread: f32,
+    pub magazine: u16,
+    pub reload_time: f32,
+    #[serde(default = "default_speed")]
+    pub speed: f32,
+}
+
+fn default_speed() -> f32 {
+    PROJECTILE_SPEED
+}
+
+#[derive(Deserialize)]
+struct WeaponFile {
+    loadout: Vec<String>,
+    #[serde(rename = "weapon")]
+    weapons: Vec<WeaponDef>,
+}
+
+pub fn load_loadout(file_path: &str) -> Result<Vec<WeaponDef>, ServerError> {
+    std::fs::read_to_string(file_path)
+        .map_err(|err| err.to_string())
+        .and_then(|x| parse_loadout(&x))
+        .map_err(|err| ServerError::Loadout(format!("{}: {}", file_path, err)))
+}
+
+fn parse_loadout(file_str: &str) -> Result<Vec<WeaponDef>, String> {
+    let file: WeaponFile = toml::from_str(file_str).map_err(|err| err.to_string())?;
+    file.loadout
+        .iter()
+        .map(|name| {
+            file.weapons
+                .iter()
+                .find(|x| &x.name == name)
+                .cloned()
+                .ok_or_else(|| format!("no weapon named {}", name))
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug)]
+pub struct WeaponState {
+    pub def: WeaponDef,
+    pub ammo: u16,
+    pub cooldown: f32,
+    pub reload: f32,
+}
+
+impl WeaponState {
+    pub fn new(def: WeaponDef) -> Self {
+        Self {
+            ammo: def.magazine,
+            def,
+            cooldown: 0.0,
+            reload: 0.0,
+        }
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        self.cooldown = (self.cooldown - dt).max(0.0);
+        if self.reload > 0.0 {
+            self.reload -= dt;
+            if self.reload <= 0.0 {
+                self.reload = 0.0;
+                self.ammo = self.def.magazine;
+            }
+        }
+    }
+
+    pub fn start_reload(&mut self) {
+        if self.reload <= 0.0 && self.ammo < self.def.magazine {
+            self.reload = self.def.reload_time;
+        }
+    }
+
+    pub fn fire(&mut self) -> bool {
+        if self.reload > 0.0 || self.cooldown > 0.0 {
+            return false;
+        }
+        if self.ammo == 0 {
+            self.start_reload();
+            return false;
+        }
+        self.ammo -= 1;
+        self.cooldown = 1.0 / self.def.fire_rate.max(f32::EPSILON);
+        true
+    }
+}
+
+pub fn apply_spread(direction: Vector3, spread: f32) -> Vector3 {
+    if spread <= 0.0 {
+        return direction;
+    }
+    let mut rng = rand::thread_rng();
+    let angle = rng.gen_range(0.0..spread.to_radians());
+    let roll = rng.gen_range(0.0..std::f32::consts::TAU);
+    let reference = if direction.y.abs() < 0.99 {
+        Vector3::up()
+    } else {
+        Vector3::right()
+    };
+    let right = direction.cross(reference).normalized();
+    let up = right.cross(direction).normalized();
+    (direction * angle.cos() + (right * roll.cos() + up * roll.sin()) * angle.sin()).normalized()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shipped_loadout_loads() {
+        let loadout = load_loadout(WEAPONS_PATH).unwrap();
+        assert!(!loadout.is_empty());
+    }
+
+    #[test]
+    fn bad_loadouts_are_errors() {
+        assert!(load_loadout("static/models/missing.toml").is_err());
+        assert!(parse_loadout("loadout = [").is_err());
+        let unknown = r#"
+            loadout = ["laser"]
+
+            [[weapon]]
+            name = "rifle"
+            mode = "hitscan"
+            damage = 1.0
+            fire_rate = 1.0
+            magazine = 1
+            reload_time = 1.0
+        "#;
+        assert!(parse_loadout(unknown).unwrap_err().contains("laser"));
+    }
+}